@@ -0,0 +1,111 @@
+//! Requests per second through the gateway's routing, at several levels of concurrency.
+//!
+//! Every request posts to the SSE message endpoint of a server with an unknown session,
+//! so it is answered by the gateway without reaching an upstream.
+
+use std::{
+    net::TcpListener,
+    process::{Child, Command, Stdio},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+const SERVERS: usize = 8;
+const DURATION: Duration = Duration::from_secs(3);
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Gateway(Child);
+
+impl Drop for Gateway {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let config = std::env::temp_dir().join(format!("mcpgate-bench-{}.json", std::process::id()));
+    let servers = (0..SERVERS)
+        .map(|i| {
+            (
+                format!("server{i}"),
+                serde_json::json!({ "type": "sse", "url": "http://127.0.0.1:9/sse" }),
+            )
+        })
+        .collect::<serde_json::Map<_, _>>();
+    std::fs::write(
+        &config,
+        serde_json::json!({ "mcpServers": servers }).to_string(),
+    )
+    .unwrap();
+
+    // Ask the OS for a free port, then release it for the gateway to bind.
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut gateway = Gateway(
+        Command::new(env!("CARGO_BIN_EXE_mcpgate"))
+            .args(["--sse", "-H", "127.0.0.1", "-P", &port.to_string(), "-C"])
+            .arg(&config)
+            .env("RUST_LOG", "error")
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    let client = reqwest::Client::new();
+    let base = format!("http://127.0.0.1:{port}");
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while client
+        .get(format!("{base}/_mcpgate/status"))
+        .send()
+        .await
+        .is_err()
+    {
+        if let Some(status) = gateway.0.try_wait().unwrap() {
+            panic!("gateway exited with {status} before it was ready");
+        }
+        assert!(
+            Instant::now() < deadline,
+            "gateway not ready after {STARTUP_TIMEOUT:?}"
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    for concurrency in [1, 8, 64, 256] {
+        let count = Arc::new(AtomicU64::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+        let tasks = (0..concurrency)
+            .map(|i| {
+                let client = client.clone();
+                let url = format!("{base}/server{}/message?sessionId=none", i % SERVERS);
+                let (count, done) = (count.clone(), done.clone());
+                tokio::spawn(async move {
+                    while !done.load(Ordering::Relaxed) {
+                        let res = client.post(&url).body("{}").send().await;
+                        if res.is_ok() {
+                            count.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let start = Instant::now();
+        tokio::time::sleep(DURATION).await;
+        done.store(true, Ordering::Relaxed);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let rate = count.load(Ordering::Relaxed) as f64 / start.elapsed().as_secs_f64();
+        println!("concurrency {concurrency:>4}: {rate:>10.0} req/s");
+    }
+
+    std::fs::remove_file(config).unwrap();
+}
@@ -0,0 +1,318 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    hash::Hash,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use rmcp::model::{
+    CallToolRequestParam, CallToolResult, ListPromptsResult, ListResourceTemplatesResult,
+    ListResourcesResult, ListToolsResult, ReadResourceResult,
+};
+use tokio::sync::RwLock;
+
+use crate::config::CacheConfig;
+
+type Cursor = Option<String>;
+
+/// Per-server cache of list results and idempotent reads.
+///
+/// List results are kept until the upstream reports a `list_changed`, resource reads until
+/// the resource is reported updated. A configured `ttl` expires every entry on top of that.
+///
+/// The cache is shared by every session of the server, whatever identity they present
+/// upstream, so it should only be enabled for servers whose answers don't depend on the
+/// client.
+#[derive(Debug, Default)]
+pub struct Cache {
+    config: Option<CacheConfig>,
+    tools: Store<Cursor, ListToolsResult>,
+    prompts: Store<Cursor, ListPromptsResult>,
+    resources: Store<Cursor, ListResourcesResult>,
+    resource_templates: Store<Cursor, ListResourceTemplatesResult>,
+    reads: Store<String, ReadResourceResult>,
+    calls: Store<(String, String), CallToolResult>,
+    idempotent_tools: RwLock<HashSet<String>>,
+}
+
+impl Cache {
+    pub fn new(config: Option<CacheConfig>) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        self.config.as_ref().and_then(|c| c.ttl())
+    }
+
+    fn lists(&self) -> bool {
+        self.config.as_ref().is_some_and(|c| c.lists())
+    }
+
+    pub async fn tools<E>(
+        &self,
+        cursor: Cursor,
+        fetch: impl Future<Output = Result<ListToolsResult, E>>,
+    ) -> Result<ListToolsResult, E> {
+        let res = self
+            .tools
+            .load(self.lists(), self.ttl(), cursor, fetch)
+            .await?;
+
+        if self.config.as_ref().is_some_and(|c| c.idempotent_tools()) {
+            let mut idempotent_tools = self.idempotent_tools.write().await;
+            for tool in res.tools.iter() {
+                // An idempotent tool may still have side effects, e.g. `delete_item`.
+                let cacheable = tool.annotations.as_ref().is_some_and(|a| {
+                    a.idempotent_hint == Some(true) && a.read_only_hint == Some(true)
+                });
+                if cacheable {
+                    idempotent_tools.insert(tool.name.to_string());
+                } else {
+                    idempotent_tools.remove(tool.name.as_ref());
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    pub async fn prompts<E>(
+        &self,
+        cursor: Cursor,
+        fetch: impl Future<Output = Result<ListPromptsResult, E>>,
+    ) -> Result<ListPromptsResult, E> {
+        self.prompts
+            .load(self.lists(), self.ttl(), cursor, fetch)
+            .await
+    }
+
+    pub async fn resources<E>(
+        &self,
+        cursor: Cursor,
+        fetch: impl Future<Output = Result<ListResourcesResult, E>>,
+    ) -> Result<ListResourcesResult, E> {
+        self.resources
+            .load(self.lists(), self.ttl(), cursor, fetch)
+            .await
+    }
+
+    pub async fn resource_templates<E>(
+        &self,
+        cursor: Cursor,
+        fetch: impl Future<Output = Result<ListResourceTemplatesResult, E>>,
+    ) -> Result<ListResourceTemplatesResult, E> {
+        self.resource_templates
+            .load(self.lists(), self.ttl(), cursor, fetch)
+            .await
+    }
+
+    pub async fn read<E>(
+        &self,
+        uri: &str,
+        fetch: impl Future<Output = Result<ReadResourceResult, E>>,
+    ) -> Result<ReadResourceResult, E> {
+        let enabled = self.config.as_ref().is_some_and(|c| c.reads());
+        self.reads
+            .load(enabled, self.ttl(), uri.to_string(), fetch)
+            .await
+    }
+
+    pub async fn call<E>(
+        &self,
+        params: &CallToolRequestParam,
+        fetch: impl Future<Output = Result<CallToolResult, E>>,
+    ) -> Result<CallToolResult, E> {
+        let enabled = self.config.as_ref().is_some_and(|c| c.idempotent_tools())
+            && self
+                .idempotent_tools
+                .read()
+                .await
+                .contains(params.name.as_ref());
+        let key = (
+            params.name.to_string(),
+            serde_json::to_string(&params.arguments).unwrap_or_default(),
+        );
+        // Errors are likely transient, so they are never replayed.
+        let res = self
+            .calls
+            .load_if(enabled, self.ttl(), key, fetch, |res| {
+                res.is_error != Some(true)
+            })
+            .await?;
+        Ok(res)
+    }
+
+    pub async fn invalidate_tools(&self) {
+        self.tools.clear().await;
+        self.calls.clear().await;
+        self.idempotent_tools.write().await.clear();
+    }
+
+    pub async fn invalidate_prompts(&self) {
+        self.prompts.clear().await;
+    }
+
+    pub async fn invalidate_resources(&self) {
+        self.resources.clear().await;
+        self.resource_templates.clear().await;
+        self.reads.clear().await;
+    }
+
+    pub async fn invalidate_read(&self, uri: &str) {
+        self.reads.remove(uri).await;
+    }
+
+    /// Writes hit and miss counters in the Prometheus text format.
+    pub fn write_metrics(&self, server: &str, out: &mut String) {
+        let stores: [(&str, &Stats); 6] = [
+            ("tools", &self.tools.stats),
+            ("prompts", &self.prompts.stats),
+            ("resources", &self.resources.stats),
+            ("resource_templates", &self.resource_templates.stats),
+            ("reads", &self.reads.stats),
+            ("calls", &self.calls.stats),
+        ];
+        for (kind, stats) in stores {
+            let _ = writeln!(
+                out,
+                "mcpgate_cache_hits_total{{server=\"{server}\",kind=\"{kind}\"}} {}",
+                stats.hits.load(Ordering::Relaxed)
+            );
+            let _ = writeln!(
+                out,
+                "mcpgate_cache_misses_total{{server=\"{server}\",kind=\"{kind}\"}} {}",
+                stats.misses.load(Ordering::Relaxed)
+            );
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Store<K, V> {
+    entries: RwLock<HashMap<K, (Instant, V)>>,
+    stats: Stats,
+}
+
+impl<K, V> Default for Store<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: Default::default(),
+            stats: Default::default(),
+        }
+    }
+}
+
+impl<K: Eq + Hash, V: Clone> Store<K, V> {
+    async fn load<E>(
+        &self,
+        enabled: bool,
+        ttl: Option<Duration>,
+        key: K,
+        fetch: impl Future<Output = Result<V, E>>,
+    ) -> Result<V, E> {
+        self.load_if(enabled, ttl, key, fetch, |_| true).await
+    }
+
+    /// Like `load`, but only keeps fetched values for which `keep` returns true.
+    async fn load_if<E>(
+        &self,
+        enabled: bool,
+        ttl: Option<Duration>,
+        key: K,
+        fetch: impl Future<Output = Result<V, E>>,
+        keep: impl FnOnce(&V) -> bool,
+    ) -> Result<V, E> {
+        if !enabled {
+            return fetch.await;
+        }
+
+        let cached = self.entries.read().await.get(&key).and_then(|(at, v)| {
+            let fresh = ttl.is_none_or(|ttl| at.elapsed() < ttl);
+            fresh.then(|| v.clone())
+        });
+
+        if let Some(value) = cached {
+            self.stats.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(value);
+        }
+
+        self.stats.misses.fetch_add(1, Ordering::Relaxed);
+        let value = fetch.await?;
+        if keep(&value) {
+            let mut entries = self.entries.write().await;
+            if let Some(ttl) = ttl {
+                entries.retain(|_, (at, _)| at.elapsed() < ttl);
+            }
+            entries.insert(key, (Instant::now(), value.clone()));
+        }
+        Ok(value)
+    }
+
+    async fn remove<Q>(&self, key: &Q)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.entries.write().await.remove(key);
+    }
+
+    async fn clear(&self) {
+        self.entries.write().await.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_store() {
+        let store = Store::<&str, u32>::default();
+
+        let v = store.load(true, None, "a", async { Ok::<_, Infallible>(1) });
+        assert_eq!(v.await, Ok(1));
+        let v = store.load(true, None, "a", async { Ok::<_, Infallible>(2) });
+        assert_eq!(v.await, Ok(1));
+
+        store.remove("a").await;
+        let v = store.load(true, None, "a", async { Ok::<_, Infallible>(3) });
+        assert_eq!(v.await, Ok(3));
+
+        assert_eq!(store.stats.hits.load(Ordering::Relaxed), 1);
+        assert_eq!(store.stats.misses.load(Ordering::Relaxed), 2);
+
+        let v = store.load(true, Some(Duration::ZERO), "a", async {
+            Ok::<_, Infallible>(4)
+        });
+        assert_eq!(v.await, Ok(4));
+    }
+
+    #[tokio::test]
+    async fn test_store_skips_and_prunes() {
+        let store = Store::<&str, u32>::default();
+
+        let v = store.load_if(true, None, "a", async { Ok::<_, Infallible>(1) }, |_| false);
+        assert_eq!(v.await, Ok(1));
+        assert!(store.entries.read().await.is_empty());
+
+        let v = store.load(true, None, "a", async { Ok::<_, Infallible>(2) });
+        assert_eq!(v.await, Ok(2));
+        let v = store.load(true, Some(Duration::ZERO), "b", async {
+            Ok::<_, Infallible>(3)
+        });
+        assert_eq!(v.await, Ok(3));
+        let entries = store.entries.read().await;
+        assert_eq!(entries.keys().collect::<Vec<_>>(), [&"b"]);
+    }
+}
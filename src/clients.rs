@@ -0,0 +1,156 @@
+use std::{collections::HashMap, path::Path};
+
+use serde_json::Value;
+
+/// File name of the Claude Desktop config.
+const CLAUDE_DESKTOP: &str = "claude_desktop_config.json";
+
+/// Server fields of VS Code that only concern the editor.
+const EDITOR_FIELDS: [&str; 1] = ["dev"];
+
+/// Turns the MCP config of Claude Desktop, VS Code or Cursor into one of ours, returning
+/// the values of the VS Code `inputs` it declares.
+///
+/// An input is read from the secrets file under its id, then from the environment
+/// variable named after it (`api-key` reads `API_KEY`), then from its `default`.
+pub fn normalize(
+    config: &mut Value,
+    path: &Path,
+    secrets: Option<&Path>,
+) -> anyhow::Result<HashMap<String, String>> {
+    let Value::Object(map) = config else {
+        return Ok(HashMap::new());
+    };
+
+    // Claude Desktop keeps its own settings next to the servers.
+    if path.file_name().is_some_and(|name| name == CLAUDE_DESKTOP) {
+        map.retain(|key, _| key == "mcpServers");
+    }
+
+    let inputs = match map.remove("inputs") {
+        Some(Value::Array(inputs)) => resolve_inputs(&inputs, secrets)?,
+        _ => HashMap::new(),
+    };
+
+    for key in ["mcpServers", "servers"] {
+        let Some(Value::Object(servers)) = map.get_mut(key) else {
+            continue;
+        };
+        for server in servers.values_mut() {
+            let Value::Object(server) = server else {
+                continue;
+            };
+            server.retain(|field, _| !EDITOR_FIELDS.contains(&field.as_str()));
+
+            // Cursor tells remote servers apart by their `url` alone.
+            if !server.contains_key("type") && !server.contains_key("command") {
+                let Some(url) = server.get("url").and_then(Value::as_str) else {
+                    continue;
+                };
+                let path = url.split(['?', '#']).next().unwrap_or(url);
+                let typ = if path.ends_with("/sse") {
+                    "sse"
+                } else {
+                    "streamableHttp"
+                };
+                server.insert("type".to_string(), typ.into());
+            }
+        }
+    }
+    Ok(inputs)
+}
+
+fn resolve_inputs(
+    inputs: &[Value],
+    secrets: Option<&Path>,
+) -> anyhow::Result<HashMap<String, String>> {
+    let secrets = match secrets {
+        Some(path) => {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("failed to read {}: {e}", path.display()))?;
+            crate::env::parse_dotenv(&content)
+                .map_err(|e| anyhow::anyhow!("failed to parse {}: {e}", path.display()))?
+                .into_iter()
+                .collect()
+        }
+        None => HashMap::new(),
+    };
+
+    let mut values = HashMap::new();
+    for input in inputs {
+        let Some(id) = input.get("id").and_then(Value::as_str) else {
+            continue;
+        };
+        let value = secrets
+            .get(id)
+            .cloned()
+            .or_else(|| std::env::var(env_name(id)).ok())
+            .or_else(|| input.get("default").and_then(Value::as_str).map(Into::into));
+        if let Some(value) = value {
+            values.insert(id.to_string(), value);
+        }
+    }
+    Ok(values)
+}
+
+/// Name of the environment variable an input is read from.
+fn env_name(id: &str) -> String {
+    id.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_normalize() {
+        let mut config = json!({
+            "inputs": [
+                { "type": "promptString", "id": "mcpgate-test-key", "password": true },
+                { "type": "promptString", "id": "region", "default": "eu" }
+            ],
+            "servers": {
+                "github": { "type": "http", "url": "https://api.example.com/mcp", "dev": {} },
+                "legacy": { "url": "http://localhost:8000/sse?x=1" },
+                "remote": { "url": "http://localhost:8000/mcp" },
+                "fetch": { "command": "uvx", "args": ["mcp-server-fetch"] }
+            }
+        });
+        let secrets = std::env::temp_dir().join(format!("mcpgate-secrets-{}", std::process::id()));
+        std::fs::write(&secrets, "mcpgate-test-key=s3cret\n").unwrap();
+        let inputs = normalize(&mut config, Path::new("mcp.json"), Some(&secrets)).unwrap();
+        std::fs::remove_file(secrets).unwrap();
+
+        assert_eq!(inputs["mcpgate-test-key"], "s3cret");
+        assert_eq!(inputs["region"], "eu");
+        assert!(config.get("inputs").is_none());
+        let servers = &config["servers"];
+        assert_eq!(
+            servers["github"],
+            json!({ "type": "http", "url": "https://api.example.com/mcp" })
+        );
+        assert_eq!(servers["legacy"]["type"], "sse");
+        assert_eq!(servers["remote"]["type"], "streamableHttp");
+        assert!(servers["fetch"].get("type").is_none());
+        assert_eq!(env_name("api-key"), "API_KEY");
+    }
+
+    #[test]
+    fn test_claude_desktop() {
+        let mut config = json!({
+            "globalShortcut": "Ctrl+Space",
+            "mcpServers": { "fetch": { "command": "uvx", "args": ["mcp-server-fetch"] } }
+        });
+        normalize(&mut config, Path::new(CLAUDE_DESKTOP), None).unwrap();
+        assert_eq!(config.as_object().unwrap().len(), 1);
+    }
+}
@@ -1,47 +1,411 @@
-use rmcp::transport::{ConfigureCommandExt, TokioChildProcess};
 use rmcp::{
-    RoleClient, ServiceExt,
-    model::{ClientCapabilities, ClientInfo, Implementation, InitializeRequestParam},
-    service::RunningService,
-    transport::{SseClientTransport, StreamableHttpClientTransport},
+    ServiceExt,
+    model::{ClientCapabilities, ClientInfo, Implementation},
+    transport::{
+        SseClientTransport, StreamableHttpClientTransport, sse_client::SseClientConfig,
+        streamable_http_client::StreamableHttpClientTransportConfig,
+    },
 };
-use serde::de::IntoDeserializer;
 use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
 use std::path::{Path, PathBuf};
-use std::{collections::HashMap, sync::Arc};
+use std::time::Duration;
+use std::{collections::HashMap, io, sync::Arc};
 use tokio::process::Command;
 
+use crate::clients;
+use crate::env;
 use crate::error::Error;
+use crate::interpolate;
+use crate::process::{StderrSink, StdioProcess};
+use crate::upstream::{Upstream, UpstreamClient};
+use crate::validate::Problems;
+use crate::workdir::SessionDir;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct Config {
     #[serde(rename = "mcpServers", alias = "servers", alias = "mcpServers")]
     pub servers: HashMap<Arc<str>, Arc<McpServerConfig>>,
+    /// Globs, relative to this file, of further config files whose servers are merged in.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Every file the config was read from.
+    #[serde(skip)]
+    pub sources: Vec<PathBuf>,
+    /// Fail on undefined `${VAR}` instead of expanding it to an empty string.
+    #[serde(
+        rename = "strictVariables",
+        default,
+        skip_serializing_if = "is_default"
+    )]
+    pub strict_variables: bool,
+    /// Reject unknown fields, missing directories and commands instead of warning.
+    #[serde(
+        rename = "strictValidation",
+        default,
+        skip_serializing_if = "is_default"
+    )]
+    pub strict_validation: bool,
 }
 
 impl Config {
-    pub fn read<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        Ok(serde_json::from_reader(std::fs::File::open(&path)?)?)
+    /// JSON Schema of config files, for editors to complete and validate them.
+    #[cfg(feature = "schemars")]
+    pub fn schema() -> schemars::schema::RootSchema {
+        schemars::schema_for!(Config)
+    }
+
+    /// Reads a config along with the files it includes and those in the `config.d`
+    /// directory next to it, taking VS Code inputs from the `secrets` dotenv file.
+    ///
+    /// Only the main file's `include`, `strictVariables` and `strictValidation` apply, to
+    /// every file read.
+    pub fn read<P: AsRef<Path>>(path: P, secrets: Option<&Path>) -> anyhow::Result<Self> {
+        let path = std::fs::canonicalize(path)?;
+        let dir = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+        let mut problems = Problems::default();
+        let mut config = Self::read_file(&path, secrets, None, &mut problems)?;
+
+        let mut files = Vec::new();
+        for pattern in &config.include {
+            for file in glob::glob(&dir.join(pattern).to_string_lossy())? {
+                files.push(file?);
+            }
+        }
+        let drop_in = dir.join(DROP_IN_DIR);
+        if drop_in.is_dir() {
+            let mut entries = std::fs::read_dir(&drop_in)?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            entries.retain(|p| p.is_file() && ConfigFormat::is_config(p));
+            entries.sort();
+            files.extend(entries);
+        }
+
+        let mut origins = config
+            .servers
+            .keys()
+            .map(|name| (name.clone(), path.clone()))
+            .collect::<HashMap<_, _>>();
+        config.sources.push(path);
+        for file in files {
+            let file = std::fs::canonicalize(file)?;
+            if config.sources.contains(&file) {
+                continue;
+            }
+            let included =
+                Self::read_file(&file, secrets, Some(config.strict_variables), &mut problems)?;
+            for (field, set) in [
+                ("include", !included.include.is_empty()),
+                ("strictVariables", included.strict_variables),
+                ("strictValidation", included.strict_validation),
+            ] {
+                if set {
+                    problems.warn(format!(
+                        "{}: `{field}` only applies in the main config file",
+                        file.display()
+                    ));
+                }
+            }
+            for (name, server) in included.servers {
+                if let Some(origin) = origins.get(&name) {
+                    anyhow::bail!(
+                        "server `{name}` is defined in both {} and {}",
+                        origin.display(),
+                        file.display()
+                    );
+                }
+                origins.insert(name.clone(), file.clone());
+                config.servers.insert(name, server);
+            }
+            config.sources.push(file);
+        }
+
+        problems.check(&config);
+        problems.finish(config.strict_validation)?;
+        Ok(config)
+    }
+
+    /// Reads a single JSON, YAML or TOML file, chosen by its extension.
+    ///
+    /// `strict_variables` overrides the file's own setting for included files.
+    fn read_file(
+        path: &Path,
+        secrets: Option<&Path>,
+        strict_variables: Option<bool>,
+        problems: &mut Problems,
+    ) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read {}: {e}", path.display()))?;
+        let mut config = ConfigFormat::from_path(path)
+            .parse(&content)
+            .map_err(|e| anyhow::anyhow!("failed to parse {}: {e}", path.display()))?;
+        let inputs = clients::normalize(&mut config, path, secrets)?;
+        let strict_variables = strict_variables.unwrap_or_else(|| {
+            config
+                .get("strictVariables")
+                .and_then(serde_json::Value::as_bool)
+                .unwrap_or_default()
+        });
+        interpolate::config(
+            &mut config,
+            path.parent().unwrap_or(Path::new("/")),
+            &inputs,
+            strict_variables,
+        )?;
+        problems.unknown_fields(path, &config);
+        let raw = config.clone();
+        serde_path_to_error::deserialize(config).map_err(|e| {
+            let mut keys = PathKey::from_path(e.path());
+            let inner = match keys.as_slice() {
+                [PathKey::Field(servers), PathKey::Field(name)] => raw
+                    .get(servers)
+                    .and_then(|s| s.get(name))
+                    .map(McpServerConfig::error_path)
+                    .unwrap_or_default(),
+                _ => Vec::new(),
+            };
+            keys.extend(inner);
+            let location = ConfigFormat::from_path(path)
+                .locate(&content, &keys)
+                .map(|(line, column)| format!(" (near line {line}, column {column})"))
+                .unwrap_or_default();
+            anyhow::anyhow!(
+                "invalid config {}{location}: {}: {}",
+                path.display(),
+                e.path(),
+                e.inner()
+            )
+        })
+    }
+
+    /// Paths to watch for hot reload, with whether to watch their subdirectories too:
+    /// every source file, the drop-in directory, or the config directory until it exists,
+    /// and the directories the `include` globs match files in.
+    pub fn watch_paths(&self) -> Vec<(PathBuf, bool)> {
+        let mut paths = self
+            .sources
+            .iter()
+            .map(|p| (p.clone(), false))
+            .collect::<Vec<_>>();
+        let Some(dir) = self.sources.first().and_then(|p| p.parent()) else {
+            return paths;
+        };
+
+        let drop_in = dir.join(DROP_IN_DIR);
+        if drop_in.is_dir() {
+            paths.push((drop_in, false));
+        } else {
+            paths.push((dir.to_path_buf(), false));
+        }
+        for pattern in &self.include {
+            let (base, recursive) = glob_base(&dir.join(pattern));
+            if !base.is_dir() {
+                continue;
+            }
+            match paths.iter_mut().find(|(p, _)| *p == base) {
+                Some((_, r)) => *r |= recursive,
+                None => paths.push((base, recursive)),
+            }
+        }
+        paths
+    }
+}
+
+/// Splits a glob into the directory its matches are under, the part before the first
+/// wildcard, and whether they can be nested deeper through `**`.
+fn glob_base(pattern: &Path) -> (PathBuf, bool) {
+    let is_wildcard =
+        |c: &std::path::Component| c.as_os_str().to_string_lossy().contains(['*', '?', '[']);
+    let base = pattern
+        .components()
+        .take_while(|c| !is_wildcard(c))
+        .collect::<PathBuf>();
+    let recursive = pattern.components().any(|c| c.as_os_str() == "**");
+    if base == pattern {
+        let parent = pattern.parent().unwrap_or(pattern).to_path_buf();
+        return (parent, false);
+    }
+    (base, recursive)
+}
+
+/// Directory next to the config file whose config files are merged in.
+const DROP_IN_DIR: &str = "config.d";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    fn is_config(path: &Path) -> bool {
+        matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("json" | "yaml" | "yml" | "toml")
+        )
+    }
+
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => Self::Yaml,
+            Some("toml") => Self::Toml,
+            _ => Self::Json,
+        }
+    }
+
+    /// Finds the line and column of the value at `path` in the raw content, or of the
+    /// end of the deepest map or sequence on the way if it isn't there.
+    fn locate(self, content: &str, path: &[PathKey]) -> Option<(usize, usize)> {
+        use serde::de::DeserializeSeed;
+
+        match self {
+            Self::Json => Probe(path)
+                .deserialize(&mut serde_json::Deserializer::from_str(content))
+                .err()
+                .map(|e| (e.line(), e.column())),
+            Self::Yaml => Probe(path)
+                .deserialize(serde_yaml::Deserializer::from_str(content))
+                .err()
+                .and_then(|e| e.location())
+                .map(|l| (l.line(), l.column())),
+            Self::Toml => Probe(path)
+                .deserialize(toml::Deserializer::new(content))
+                .err()
+                .and_then(|e| e.span())
+                .map(|span| {
+                    let before = &content[..span.start];
+                    let line = before.matches('\n').count() + 1;
+                    let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+                    (line, column)
+                }),
+        }
+    }
+
+    /// Parses into a JSON value so that every format goes through the same deserializers.
+    fn parse(self, content: &str) -> anyhow::Result<serde_json::Value> {
+        Ok(match self {
+            Self::Json => serde_json::from_str(content)?,
+            Self::Yaml => serde_yaml::from_str(content)?,
+            Self::Toml => toml::from_str(content)?,
+        })
+    }
+}
+
+/// Step of the path to a value in a config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PathKey {
+    Field(String),
+    Index(usize),
+}
+
+impl PathKey {
+    /// Converts a path up to its first step that is neither a map key nor an index.
+    fn from_path(path: &serde_path_to_error::Path) -> Vec<Self> {
+        use serde_path_to_error::Segment;
+
+        path.iter()
+            .map_while(|segment| match segment {
+                Segment::Map { key } => Some(Self::Field(key.clone())),
+                Segment::Seq { index } => Some(Self::Index(*index)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Walks raw content down a path and fails on the value it leads to, for the
+/// deserializer to report where that value is.
+struct Probe<'a>(&'a [PathKey]);
+
+impl<'de> serde::de::DeserializeSeed<'de> for Probe<'_> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for Probe<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a value to locate")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<(), A::Error>
+    where
+        A: serde::de::MapAccess<'de>,
+    {
+        use serde::de::{Error, IgnoredAny};
+
+        if let Some((PathKey::Field(field), rest)) = self.0.split_first() {
+            while let Some(key) = map.next_key::<String>()? {
+                if key == *field {
+                    return map.next_value_seed(Probe(rest));
+                }
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Err(A::Error::custom("located"))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<(), A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        use serde::de::{Error, IgnoredAny};
+
+        if let Some((PathKey::Index(index), rest)) = self.0.split_first() {
+            for _ in 0..*index {
+                if seq.next_element::<IgnoredAny>()?.is_none() {
+                    return Err(A::Error::custom("located"));
+                }
+            }
+            if seq.next_element_seed(Probe(rest))?.is_some() {
+                return Ok(());
+            }
+        }
+        Err(A::Error::custom("located"))
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct McpSseConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     name: Option<Arc<str>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     description: Option<Arc<str>>,
     url: Arc<str>,
+    /// Headers sent with every request to the server, e.g. for auth.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    headers: HashMap<String, String>,
+    #[serde(flatten)]
+    options: McpServerOptions,
+    /// Fields none of the others take, reported by validation.
+    #[serde(flatten, skip_serializing)]
+    #[cfg_attr(feature = "schemars", schemars(skip))]
+    unknown: HashMap<String, serde_json::Value>,
 }
 
 impl McpSseConfig {
-    async fn create_client(
-        &self,
-        client_info: ClientInfo,
-    ) -> Result<Arc<RunningService<RoleClient, InitializeRequestParam>>, Error> {
-        let transport = SseClientTransport::start(self.url.clone()).await?;
+    async fn create_client(&self, upstream: Upstream) -> Result<Arc<UpstreamClient>, Error> {
+        let transport = SseClientTransport::start_with_client(
+            http_client(&self.headers)?,
+            SseClientConfig {
+                sse_endpoint: self.url.clone(),
+                ..Default::default()
+            },
+        )
+        .await?;
 
-        let client = client_info
+        let client = upstream
             .serve(transport)
             .await
             .map(Arc::new)
@@ -58,6 +422,10 @@ impl McpSseConfig {
     pub fn description(&self) -> Option<&str> {
         self.description.as_deref()
     }
+
+    pub fn options(&self) -> &McpServerOptions {
+        &self.options
+    }
 }
 
 impl<T: Into<Arc<str>>> From<T> for McpSseConfig {
@@ -66,11 +434,15 @@ impl<T: Into<Arc<str>>> From<T> for McpSseConfig {
             url: value.into(),
             name: None,
             description: None,
+            headers: Default::default(),
+            options: Default::default(),
+            unknown: Default::default(),
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct McpStdioConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     name: Option<Arc<str>>,
@@ -78,30 +450,74 @@ pub struct McpStdioConfig {
     description: Option<Arc<str>>,
     command: String,
     args: Vec<String>,
-    cwd: Option<PathBuf>,
-    env: Option<HashMap<String, String>>,
+    cwd: Option<WorkingDir>,
+    env: Option<HashMap<String, EnvValue>>,
+    /// Which variables of the gateway environment the server inherits.
+    #[serde(rename = "inheritEnv", default, skip_serializing_if = "is_default")]
+    inherit_env: InheritEnv,
+    /// Dotenv file loaded on top of the inherited variables.
+    #[serde(rename = "envFile", default, skip_serializing_if = "Option::is_none")]
+    env_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    stderr: StderrConfig,
+    /// Seconds between SIGTERM and SIGKILL when the server is stopped.
+    #[serde(
+        rename = "gracePeriod",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    grace_period: Option<u64>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    sandbox: Box<SandboxConfig>,
+    #[serde(flatten)]
+    options: McpServerOptions,
+    /// Fields none of the others take, reported by validation.
+    #[serde(flatten, skip_serializing)]
+    #[cfg_attr(feature = "schemars", schemars(skip))]
+    unknown: HashMap<String, serde_json::Value>,
 }
 
 impl McpStdioConfig {
     async fn create_client(
         &self,
-        client_info: ClientInfo,
-    ) -> Result<Arc<RunningService<RoleClient, InitializeRequestParam>>, Error> {
-        let client = client_info
-            .serve(TokioChildProcess::new(
-                Command::new(&self.command).configure(|cmd| {
-                    for arg in &self.args {
-                        cmd.arg(arg);
-                    }
-                    if let Some(cwd) = self.cwd.as_deref() {
-                        cmd.current_dir(cwd);
-                    }
-                    if let Some(env) = self.env.as_ref() {
-                        for (n, v) in env.iter() {
-                            cmd.env(n, v);
-                        }
-                    }
-                }),
+        upstream: Upstream,
+        stderr: StderrSink,
+    ) -> Result<Arc<UpstreamClient>, Error> {
+        let session_dir = match self.cwd.as_ref() {
+            Some(WorkingDir::Session(config)) => Some(SessionDir::create(config, &self.sandbox)?),
+            _ => None,
+        };
+        let expand = |value: &str| match session_dir.as_ref() {
+            Some(dir) => dir.expand(value),
+            None => value.to_string(),
+        };
+
+        let mut cmd = Command::new(&self.command);
+        cmd.args(self.args.iter().map(|arg| expand(arg)));
+        match (self.cwd.as_ref(), session_dir.as_ref()) {
+            (_, Some(dir)) => {
+                cmd.current_dir(dir.path());
+            }
+            (Some(WorkingDir::Path(cwd)), None) => {
+                cmd.current_dir(cwd);
+            }
+            _ => (),
+        }
+        env::apply(
+            &mut cmd,
+            &self.inherit_env,
+            self.env_file.as_deref(),
+            self.env.as_ref(),
+            expand,
+        )?;
+
+        let client = upstream
+            .serve(StdioProcess::spawn(
+                cmd,
+                stderr,
+                self.grace_period(),
+                &self.sandbox,
+                session_dir,
             )?)
             .await
             .map(Arc::new)?;
@@ -116,24 +532,303 @@ impl McpStdioConfig {
     pub fn description(&self) -> Option<&str> {
         self.description.as_deref()
     }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    pub fn cwd(&self) -> Option<&WorkingDir> {
+        self.cwd.as_ref()
+    }
+
+    pub fn stderr(&self) -> &StderrConfig {
+        &self.stderr
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period.unwrap_or(5))
+    }
+
+    pub fn options(&self) -> &McpServerOptions {
+        &self.options
+    }
+}
+
+/// Which variables of the gateway environment a stdio server inherits.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum InheritEnv {
+    #[default]
+    All,
+    None,
+    Allowlist(Vec<String>),
+}
+
+/// Value of an environment variable of a stdio server.
+///
+/// Literal values are treated as secrets and redacted when serialized or printed.
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+pub enum EnvValue {
+    Value(String),
+    /// Contents of a file, without the trailing newline.
+    File {
+        file: PathBuf,
+    },
+    /// A variable of the gateway environment.
+    Env {
+        env: String,
+    },
+}
+
+const REDACTED: &str = "<redacted>";
+
+impl Serialize for EnvValue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeMap;
+
+        let (key, value) = match self {
+            EnvValue::Value(_) => return serializer.serialize_str(REDACTED),
+            EnvValue::File { file } => ("file", file.to_string_lossy()),
+            EnvValue::Env { env } => ("env", env.into()),
+        };
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry(key, &value)?;
+        map.end()
+    }
+}
+
+impl std::fmt::Debug for EnvValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvValue::Value(_) => f.write_str(REDACTED),
+            EnvValue::File { file } => f.debug_struct("File").field("file", file).finish(),
+            EnvValue::Env { env } => f.debug_struct("Env").field("env", env).finish(),
+        }
+    }
+}
+
+/// Working directory of a stdio server: a fixed path, or a fresh directory per session.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+pub enum WorkingDir {
+    Path(PathBuf),
+    Session(SessionDirConfig),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct SessionDirConfig {
+    mode: SessionDirMode,
+    /// Directory whose contents are copied into every new session directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    template: Option<PathBuf>,
+    /// Where session directories are created, defaults to the system temp directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    root: Option<PathBuf>,
+    /// Keep the directory after the session ends, for debugging.
+    #[serde(default, skip_serializing_if = "is_default")]
+    keep: bool,
+}
+
+impl SessionDirConfig {
+    pub fn template(&self) -> Option<&Path> {
+        self.template.as_deref()
+    }
+
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    pub fn keep(&self) -> bool {
+        self.keep
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+enum SessionDirMode {
+    Session,
+}
+
+/// Limits and privileges applied to a stdio server before it starts.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct SandboxConfig {
+    /// Maximum address space in bytes (`RLIMIT_AS`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    memory: Option<u64>,
+    /// Maximum CPU time in seconds (`RLIMIT_CPU`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cpu_time: Option<u64>,
+    /// Maximum number of open files (`RLIMIT_NOFILE`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    open_files: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gid: Option<u32>,
+    /// Keep the server from gaining privileges through setuid binaries.
+    #[serde(default, skip_serializing_if = "is_default")]
+    no_new_privs: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cgroup: Option<CgroupConfig>,
+}
+
+impl SandboxConfig {
+    pub fn memory(&self) -> Option<u64> {
+        self.memory
+    }
+
+    pub fn cpu_time(&self) -> Option<u64> {
+        self.cpu_time
+    }
+
+    pub fn open_files(&self) -> Option<u64> {
+        self.open_files
+    }
+
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    pub fn gid(&self) -> Option<u32> {
+        self.gid
+    }
+
+    pub fn no_new_privs(&self) -> bool {
+        self.no_new_privs
+    }
+
+    pub fn cgroup(&self) -> Option<&CgroupConfig> {
+        self.cgroup.as_ref()
+    }
+}
+
+/// A cgroup v2 created for each server process under a parent delegated to the gateway.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct CgroupConfig {
+    parent: PathBuf,
+    /// `memory.max` in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    memory_max: Option<u64>,
+    /// `cpu.max` as a percentage of one CPU.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cpu_percent: Option<NonZeroU32>,
+}
+
+impl CgroupConfig {
+    pub fn parent(&self) -> &Path {
+        &self.parent
+    }
+
+    pub fn memory_max(&self) -> Option<u64> {
+        self.memory_max
+    }
+
+    pub fn cpu_percent(&self) -> Option<NonZeroU32> {
+        self.cpu_percent
+    }
+}
+
+/// How the stderr of a stdio server is logged.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct StderrConfig {
+    /// Level of lines not matched by any rule.
+    #[serde(default)]
+    level: LogLevel,
+    /// Rules checked in order; the first one whose text occurs in a line picks its level.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rules: Vec<StderrRule>,
+    /// Number of recent lines kept for the status endpoint.
+    #[serde(default = "default_stderr_lines")]
+    lines: usize,
+}
+
+impl Default for StderrConfig {
+    fn default() -> Self {
+        Self {
+            level: Default::default(),
+            rules: Default::default(),
+            lines: default_stderr_lines(),
+        }
+    }
+}
+
+impl StderrConfig {
+    pub fn level_of(&self, line: &str) -> LogLevel {
+        self.rules
+            .iter()
+            .find(|rule| line.contains(&rule.contains))
+            .map_or(self.level, |rule| rule.level)
+    }
+
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StderrRule {
+    contains: String,
+    level: LogLevel,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct McpStreamableConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     name: Option<Arc<str>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     description: Option<Arc<str>>,
     url: Arc<str>,
+    /// Headers sent with every request to the server, e.g. for auth.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    headers: HashMap<String, String>,
+    #[serde(flatten)]
+    options: McpServerOptions,
+    /// Fields none of the others take, reported by validation.
+    #[serde(flatten, skip_serializing)]
+    #[cfg_attr(feature = "schemars", schemars(skip))]
+    unknown: HashMap<String, serde_json::Value>,
 }
 
 impl McpStreamableConfig {
-    async fn create_client(
-        &self,
-        client_info: ClientInfo,
-    ) -> Result<Arc<RunningService<RoleClient, InitializeRequestParam>>, Error> {
-        let transport = StreamableHttpClientTransport::from_uri(self.url.clone());
-        let client = client_info
+    async fn create_client(&self, upstream: Upstream) -> Result<Arc<UpstreamClient>, Error> {
+        let transport = StreamableHttpClientTransport::with_client(
+            http_client(&self.headers)?,
+            StreamableHttpClientTransportConfig {
+                uri: self.url.clone(),
+                ..Default::default()
+            },
+        );
+        let client = upstream
             .serve(transport)
             .await
             .map(Arc::new)
@@ -151,6 +846,10 @@ impl McpStreamableConfig {
     pub fn description(&self) -> Option<&str> {
         self.description.as_deref()
     }
+
+    pub fn options(&self) -> &McpServerOptions {
+        &self.options
+    }
 }
 
 impl<T: Into<Arc<str>>> From<T> for McpStreamableConfig {
@@ -159,10 +858,15 @@ impl<T: Into<Arc<str>>> From<T> for McpStreamableConfig {
             url: value.into(),
             name: None,
             description: None,
+            headers: Default::default(),
+            options: Default::default(),
+            unknown: Default::default(),
         }
     }
 }
 
+// Configs are parsed once and shared behind an `Arc`, so the variant size doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Debug, Clone, PartialEq, Eq)]
 #[serde(tag = "type")]
 pub enum McpServerConfig {
@@ -170,27 +874,29 @@ pub enum McpServerConfig {
     Sse(McpSseConfig),
     #[serde(rename = "stdio")]
     Stdio(McpStdioConfig),
-    #[serde(rename = "streamableHttp", alias = "streamable")]
+    #[serde(rename = "streamableHttp", alias = "streamable", alias = "http")]
     Streamable(McpStreamableConfig),
 }
 
 impl McpServerConfig {
+    /// Resolves the client info presented upstream according to the configured identity.
+    pub fn client_info(&self, downstream: Option<ClientInfo>) -> ClientInfo {
+        match self.options().identity() {
+            Some(identity) => identity.resolve(downstream),
+            None => ClientIdentity::default().resolve(downstream),
+        }
+    }
+
+    /// Starts the server and initializes a client for it; `stderr` only applies to stdio.
     pub async fn create_client(
         &self,
-        client_info: Option<ClientInfo>,
-    ) -> Result<Arc<RunningService<RoleClient, InitializeRequestParam>>, Error> {
-        let client_info = client_info.unwrap_or_else(|| ClientInfo {
-            protocol_version: Default::default(),
-            capabilities: ClientCapabilities::default(),
-            client_info: Implementation {
-                name: "test sse client".to_string(),
-                version: "0.0.1".to_string(),
-            },
-        });
+        upstream: Upstream,
+        stderr: StderrSink,
+    ) -> Result<Arc<UpstreamClient>, Error> {
         match self {
-            McpServerConfig::Sse(config) => config.create_client(client_info).await,
-            McpServerConfig::Stdio(config) => config.create_client(client_info).await,
-            McpServerConfig::Streamable(config) => config.create_client(client_info).await,
+            McpServerConfig::Sse(config) => config.create_client(upstream).await,
+            McpServerConfig::Stdio(config) => config.create_client(upstream, stderr).await,
+            McpServerConfig::Streamable(config) => config.create_client(upstream).await,
         }
     }
 
@@ -199,6 +905,9 @@ impl McpServerConfig {
             name: self.name().map(|s| s.into()),
             description: self.description().map(|s| s.into()),
             url: url.into(),
+            headers: Default::default(),
+            options: Default::default(),
+            unknown: Default::default(),
         })
     }
 
@@ -207,6 +916,9 @@ impl McpServerConfig {
             name: self.name().map(|s| s.into()),
             description: self.description().map(|s| s.into()),
             url: url.into(),
+            headers: Default::default(),
+            options: Default::default(),
+            unknown: Default::default(),
         })
     }
 
@@ -225,8 +937,215 @@ impl McpServerConfig {
             McpServerConfig::Streamable(c) => c.description(),
         }
     }
+
+    /// Stderr handling of a stdio server, the defaults for other transports.
+    pub fn stderr(&self) -> StderrConfig {
+        match self {
+            McpServerConfig::Stdio(c) => c.stderr().clone(),
+            _ => Default::default(),
+        }
+    }
+
+    pub fn options(&self) -> &McpServerOptions {
+        match self {
+            McpServerConfig::Sse(c) => c.options(),
+            McpServerConfig::Stdio(c) => c.options(),
+            McpServerConfig::Streamable(c) => c.options(),
+        }
+    }
+}
+
+/// Gateway behaviour that applies to a server regardless of its transport.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct McpServerOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    identity: Option<ClientIdentity>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pagination: Pagination,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cache: Option<CacheConfig>,
+    /// Serve every session from one upstream connection instead of one per session.
+    #[serde(default, skip_serializing_if = "is_default")]
+    share_upstream: bool,
+    /// Tear down the upstream of a session idle for this many seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idle_timeout: Option<u64>,
+    /// Close a session this many seconds after it started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_session_lifetime: Option<u64>,
+    /// Number of initialized upstream clients kept ready for new sessions.
+    #[serde(default, skip_serializing_if = "is_default")]
+    prewarm: usize,
+}
+
+impl McpServerOptions {
+    pub fn identity(&self) -> Option<&ClientIdentity> {
+        self.identity.as_ref()
+    }
+
+    pub fn pagination(&self) -> Pagination {
+        self.pagination
+    }
+
+    pub fn cache(&self) -> Option<&CacheConfig> {
+        self.cache.as_ref()
+    }
+
+    pub fn share_upstream(&self) -> bool {
+        self.share_upstream
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout.map(Duration::from_secs)
+    }
+
+    pub fn max_session_lifetime(&self) -> Option<Duration> {
+        self.max_session_lifetime.map(Duration::from_secs)
+    }
+
+    pub fn prewarm(&self) -> usize {
+        self.prewarm
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct CacheConfig {
+    /// Cache `tools/list`, `prompts/list`, `resources/list` and `resources/templates/list`.
+    #[serde(default = "default_true")]
+    lists: bool,
+    /// Cache `resources/read`.
+    #[serde(default)]
+    reads: bool,
+    /// Cache `tools/call` for tools annotated with both `idempotentHint` and `readOnlyHint`.
+    #[serde(default)]
+    idempotent_tools: bool,
+    /// Expire entries after this many seconds, otherwise they live until invalidated.
+    /// Required with `reads` or `idempotentTools`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl: Option<u64>,
+}
+
+impl CacheConfig {
+    pub fn lists(&self) -> bool {
+        self.lists
+    }
+
+    pub fn reads(&self) -> bool {
+        self.reads
+    }
+
+    pub fn idempotent_tools(&self) -> bool {
+        self.idempotent_tools
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl.map(Duration::from_secs)
+    }
+}
+
+/// How `*/list` requests are paged through the gateway.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum Pagination {
+    /// Forward the client's cursor to the upstream and return its `nextCursor` untouched.
+    #[default]
+    Passthrough,
+    /// Collect every page from the upstream and answer with a single page.
+    Buffered,
 }
 
+/// How the gateway presents itself to the upstream server.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum IdentityMode {
+    /// Forward the downstream client info verbatim.
+    #[default]
+    Passthrough,
+    /// Always present the gateway's own identity.
+    Gateway,
+    /// Keep the downstream client name and append `via <gateway>/<version>`.
+    Via,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ClientIdentity {
+    #[serde(default)]
+    mode: IdentityMode,
+    /// Gateway name, defaults to `mcpgate`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// Gateway version, defaults to the version of this build.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    /// Capabilities advertised upstream; the downstream ones are forwarded when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    capabilities: Option<Capabilities>,
+}
+
+impl ClientIdentity {
+    fn gateway(&self) -> Implementation {
+        Implementation {
+            name: self
+                .name
+                .clone()
+                .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string()),
+            version: self
+                .version
+                .clone()
+                .unwrap_or_else(|| env!("CARGO_PKG_VERSION").to_string()),
+        }
+    }
+
+    /// Builds the client info sent upstream from the downstream one, if any.
+    pub fn resolve(&self, downstream: Option<ClientInfo>) -> ClientInfo {
+        let gateway = self.gateway();
+        let mut info = downstream.clone().unwrap_or_else(|| ClientInfo {
+            protocol_version: Default::default(),
+            capabilities: ClientCapabilities::default(),
+            client_info: gateway.clone(),
+        });
+
+        match (self.mode, downstream) {
+            (IdentityMode::Gateway, _) => info.client_info = gateway,
+            (IdentityMode::Via, Some(downstream)) => {
+                info.client_info.name = format!(
+                    "{} via {}/{}",
+                    downstream.client_info.name, gateway.name, gateway.version
+                );
+            }
+            (IdentityMode::Via | IdentityMode::Passthrough, _) => (),
+        }
+
+        if let Some(capabilities) = self.capabilities.as_ref() {
+            info.capabilities = capabilities.0.clone();
+        }
+
+        info
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(transparent)]
+pub struct Capabilities(
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(with = "serde_json::Map<String, serde_json::Value>")
+    )]
+    ClientCapabilities,
+);
+
+// Capabilities are parsed from config files, so they never contain NaN.
+impl Eq for Capabilities {}
+
 impl From<McpSseConfig> for McpServerConfig {
     fn from(value: McpSseConfig) -> Self {
         Self::Sse(value)
@@ -245,53 +1164,229 @@ impl From<McpStreamableConfig> for McpServerConfig {
     }
 }
 
+const SSE: &str = "sse";
+const STREAMABLE: &str = "streamable";
+const STREAMABLE_HTTP: &str = "streamableHttp";
+/// The VS Code name of the streamable HTTP transport.
+const HTTP: &str = "http";
+const STDIO: &str = "stdio";
+
 impl<'de> serde::Deserialize<'de> for McpServerConfig {
+    /// Picks the variant from the `type` field, defaulting to stdio when it is absent.
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        use McpServerConfig::*;
-        use serde::__private::de::Content::Map;
-        use serde::Deserialize;
-        use serde::de;
+        use serde::de::{Error as _, Unexpected};
+        use serde_json::Value;
+
+        const VARIANTS: &[&str] = &[SSE, STDIO, STREAMABLE, STREAMABLE_HTTP, HTTP];
 
-        let content = Deserialize::deserialize(deserializer)?;
+        let mut map = match Value::deserialize(deserializer)? {
+            Value::Object(map) => map,
+            value => {
+                let unexpected = match &value {
+                    Value::Null => Unexpected::Unit,
+                    Value::Bool(b) => Unexpected::Bool(*b),
+                    Value::Number(_) => Unexpected::Other("number"),
+                    Value::String(s) => Unexpected::Str(s),
+                    Value::Array(_) => Unexpected::Seq,
+                    Value::Object(_) => Unexpected::Map,
+                };
+                return Err(D::Error::invalid_type(unexpected, &"map"));
+            }
+        };
 
-        let Map(mut map) = content else {
-            return Err(de::Error::invalid_type(crate::into(&content), &"map"));
+        let typ = match map.remove("type") {
+            Some(Value::String(typ)) => typ,
+            Some(_) => return Err(D::Error::custom("`type` must be a string")),
+            None => String::new(),
         };
 
-        let typ = map
-            .iter()
-            .enumerate()
-            .filter(|(_, (n, _))| matches!(n.as_str(), Some(s) if s == "type"))
-            .map(|(i, _)| i)
-            .next()
-            .map(|i| map.remove(i))
-            .map(|(_, v)| v);
+        match McpServerConfig::from_map(&typ, map, &mut |_| {}) {
+            Some(Ok(config)) => Ok(config),
+            Some(Err(e)) => {
+                let path = e.path().to_string();
+                Err(match path.as_str() {
+                    "." => D::Error::custom(e.into_inner()),
+                    path => D::Error::custom(format!("{path}: {}", e.into_inner())),
+                })
+            }
+            None => Err(D::Error::unknown_variant(&typ, VARIANTS)),
+        }
+    }
+}
+
+type PathError = serde_path_to_error::Error<serde_json::Error>;
+
+impl McpServerConfig {
+    /// Deserializes the transport named by `typ`, or `None` if there is no such transport,
+    /// passing the path of every field it skips to `ignored`.
+    fn from_map<F: FnMut(serde_ignored::Path)>(
+        typ: &str,
+        map: serde_json::Map<String, serde_json::Value>,
+        ignored: &mut F,
+    ) -> Option<Result<Self, PathError>> {
+        fn variant<T: serde::de::DeserializeOwned, F: FnMut(serde_ignored::Path)>(
+            map: serde_json::Map<String, serde_json::Value>,
+            ignored: &mut F,
+        ) -> Result<T, PathError> {
+            let value = serde_json::Value::Object(map);
+            serde_path_to_error::deserialize(serde_ignored::Deserializer::new(&value, ignored))
+        }
 
+        Some(match typ {
+            SSE => variant(map, ignored).map(Self::Sse),
+            STREAMABLE | STREAMABLE_HTTP | HTTP => variant(map, ignored).map(Self::Streamable),
+            STDIO | "" => variant(map, ignored).map(Self::Stdio),
+            _ => return None,
+        })
+    }
+
+    /// Paths of the fields of a raw server entry, at any depth, that no config type takes.
+    pub fn unknown_fields(server: &serde_json::Value) -> Vec<String> {
+        let Some(mut map) = server.as_object().cloned() else {
+            return Vec::new();
+        };
+        let typ = map.remove("type");
         let typ = typ.as_ref().and_then(|t| t.as_str()).unwrap_or_default();
+        let mut unknown = Vec::new();
+        let config = Self::from_map(typ, map.clone(), &mut |path| unknown.push(path.to_string()));
+        let Some(Ok(config)) = config else {
+            return unknown;
+        };
+        let top_level = match &config {
+            Self::Sse(c) => &c.unknown,
+            Self::Stdio(c) => &c.unknown,
+            Self::Streamable(c) => &c.unknown,
+        };
+        unknown.extend(top_level.keys().cloned());
+
+        // The options are flattened into the transport, which hides the fields of their
+        // nested objects from `serde_ignored`, so they are read once more on their own.
+        let options = serde_json::Value::Object(map);
+        let _: Result<McpServerOptions, _> = serde_ignored::deserialize(&options, |path| {
+            use serde_ignored::Path;
 
-        let deserializer = Map(map).into_deserializer();
+            if !matches!(
+                path,
+                Path::Map {
+                    parent: Path::Root,
+                    ..
+                }
+            ) {
+                unknown.push(path.to_string());
+            }
+        });
+        unknown.sort();
+        unknown
+    }
+
+    /// Path within a raw server entry to where deserializing it fails.
+    fn error_path(server: &serde_json::Value) -> Vec<PathKey> {
+        let Some(mut map) = server.as_object().cloned() else {
+            return Vec::new();
+        };
+        let typ = map.remove("type");
+        let typ = typ.as_ref().and_then(|t| t.as_str()).unwrap_or_default();
+        match Self::from_map(typ, map, &mut |_| {}) {
+            Some(Err(e)) => PathKey::from_path(e.path()),
+            _ => Vec::new(),
+        }
+    }
+}
 
-        const SSE: &str = "sse";
-        const STREAMABLE: &str = "streamable";
-        const STREAMABLE_HTTP: &str = "streamableHttp";
-        const STDIO: &str = "stdio";
+#[cfg(feature = "schemars")]
+impl schemars::JsonSchema for McpServerConfig {
+    fn schema_name() -> String {
+        "McpServerConfig".to_string()
+    }
 
-        const VARIANTS: &[&str] = &[SSE, STDIO, STREAMABLE, STREAMABLE_HTTP];
+    /// One schema per transport, told apart by `type` the way `deserialize` does.
+    fn json_schema(generator: &mut schemars::r#gen::SchemaGenerator) -> schemars::schema::Schema {
+        use schemars::{
+            JsonSchema,
+            r#gen::SchemaGenerator,
+            schema::{InstanceType, Schema, SchemaObject, SubschemaValidation},
+        };
 
-        Ok(match typ {
-            SSE => Sse(Deserialize::deserialize(deserializer)?),
-            STREAMABLE | STREAMABLE_HTTP => Streamable(Deserialize::deserialize(deserializer)?),
-            STDIO | "" => Stdio(Deserialize::deserialize(deserializer)?),
-            typ => {
-                return Err(de::Error::unknown_variant(typ, VARIANTS))?;
+        fn variant<T: JsonSchema>(
+            generator: &mut SchemaGenerator,
+            types: &[&str],
+            required: bool,
+        ) -> Schema {
+            let typ = SchemaObject {
+                instance_type: Some(InstanceType::String.into()),
+                enum_values: Some(types.iter().map(|&t| t.into()).collect()),
+                ..Default::default()
+            };
+            let mut tag = SchemaObject::default();
+            let object = tag.object();
+            object.properties.insert("type".to_string(), typ.into());
+            if required {
+                object.required.insert("type".to_string());
             }
-        })
+            SchemaObject {
+                subschemas: Some(Box::new(SubschemaValidation {
+                    all_of: Some(vec![generator.subschema_for::<T>(), tag.into()]),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            }
+            .into()
+        }
+
+        SchemaObject {
+            subschemas: Some(Box::new(SubschemaValidation {
+                one_of: Some(vec![
+                    variant::<McpStdioConfig>(generator, &[STDIO], false),
+                    variant::<McpSseConfig>(generator, &[SSE], true),
+                    variant::<McpStreamableConfig>(
+                        generator,
+                        &[STREAMABLE_HTTP, STREAMABLE, HTTP],
+                        true,
+                    ),
+                ]),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
     }
 }
 
+/// HTTP client sending the configured headers with every request.
+fn http_client(headers: &HashMap<String, String>) -> io::Result<reqwest::Client> {
+    use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::try_from(name.as_str())
+            .map_err(|e| invalid(format!("invalid header {name}: {e}")))?;
+        let mut value = HeaderValue::try_from(value.as_str())
+            .map_err(|e| invalid(format!("invalid value of header {name}: {e}")))?;
+        value.set_sensitive(true);
+        map.insert(name, value);
+    }
+    reqwest::Client::builder()
+        .default_headers(map)
+        .build()
+        .map_err(io::Error::other)
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_stderr_lines() -> usize {
+    100
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -317,8 +1412,273 @@ mod tests {
                 description: None,
                 cwd: None,
                 env: None,
+                inherit_env: Default::default(),
+                env_file: None,
+                stderr: Default::default(),
+                grace_period: None,
+                sandbox: Default::default(),
+                options: Default::default(),
+                unknown: Default::default(),
             }
             .into()
         )
     }
+
+    #[test]
+    fn test_identity_via() {
+        let input = r#"
+        {
+            "command": "echo",
+            "args": [],
+            "identity": {
+                "mode": "via",
+                "capabilities": {}
+            }
+        }
+        "#;
+
+        let config = serde_json::from_str::<McpServerConfig>(input).unwrap();
+        let identity = config.options().identity().unwrap();
+
+        let downstream = ClientInfo {
+            protocol_version: Default::default(),
+            capabilities: ClientCapabilities::builder().enable_roots().build(),
+            client_info: Implementation {
+                name: "inspector".to_string(),
+                version: "1.0.0".to_string(),
+            },
+        };
+
+        let upstream = identity.resolve(Some(downstream));
+        assert_eq!(
+            upstream.client_info.name,
+            format!("inspector via mcpgate/{}", env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(upstream.client_info.version, "1.0.0");
+        assert_eq!(upstream.capabilities, ClientCapabilities::default());
+
+        let upstream = identity.resolve(None);
+        assert_eq!(upstream.client_info.name, "mcpgate");
+    }
+
+    #[test]
+    fn test_pagination() {
+        let input = r#"{ "type": "sse", "url": "http://localhost/sse", "pagination": "buffered" }"#;
+        let config = serde_json::from_str::<McpServerConfig>(input).unwrap();
+        assert_eq!(config.options().pagination(), Pagination::Buffered);
+
+        let config = serde_json::to_value(config.to_streamable("http://localhost/mcp")).unwrap();
+        assert!(config.get("pagination").is_none());
+    }
+
+    #[test]
+    fn test_stderr_rules() {
+        let input = r#"
+        {
+            "command": "echo",
+            "args": [],
+            "stderr": {
+                "level": "debug",
+                "rules": [
+                    { "contains": "Traceback", "level": "error" },
+                    { "contains": "WARN", "level": "warn" }
+                ],
+                "lines": 10
+            }
+        }
+        "#;
+
+        let McpServerConfig::Stdio(config) = serde_json::from_str(input).unwrap() else {
+            panic!("expected a stdio server");
+        };
+        let stderr = config.stderr();
+        assert_eq!(
+            stderr.level_of("Traceback (most recent call last):"),
+            LogLevel::Error
+        );
+        assert_eq!(stderr.level_of("[WARN] slow start"), LogLevel::Warn);
+        assert_eq!(stderr.level_of("listening on stdio"), LogLevel::Debug);
+        assert_eq!(stderr.lines(), 10);
+    }
+
+    #[test]
+    fn test_cgroup_cpu_percent() {
+        let sandbox = |cpu_percent: i64| {
+            serde_json::from_value::<SandboxConfig>(serde_json::json!({
+                "cgroup": { "parent": "/sys/fs/cgroup/mcpgate", "cpuPercent": cpu_percent }
+            }))
+        };
+        let config = sandbox(50).unwrap();
+        assert_eq!(config.cgroup().unwrap().cpu_percent().unwrap().get(), 50);
+        assert!(sandbox(0).unwrap_err().to_string().contains("nonzero"));
+        assert!(sandbox(-1).is_err());
+    }
+
+    #[test]
+    fn test_config_formats() {
+        let yaml = r#"
+mcpServers:
+  # comments are allowed
+  memory:
+    command: npx
+    args:
+      - -y
+      - "@modelcontextprotocol/server-memory"
+  search:
+    type: sse
+    url: http://localhost/sse
+"#;
+        let toml = r#"
+[mcpServers.memory]
+command = "npx"
+args = ["-y", "@modelcontextprotocol/server-memory"]
+
+[mcpServers.search]
+type = "sse"
+url = "http://localhost/sse"
+"#;
+
+        for (format, content) in [(ConfigFormat::Yaml, yaml), (ConfigFormat::Toml, toml)] {
+            let config: Config = serde_json::from_value(format.parse(content).unwrap()).unwrap();
+            assert!(matches!(
+                *config.servers["memory"],
+                McpServerConfig::Stdio(_)
+            ));
+            assert!(matches!(*config.servers["search"], McpServerConfig::Sse(_)));
+        }
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("config.yml")),
+            ConfigFormat::Yaml
+        );
+    }
+
+    #[test]
+    fn test_includes() {
+        let dir = std::env::temp_dir().join(format!("mcpgate-includes-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("teams")).unwrap();
+        std::fs::create_dir_all(dir.join(DROP_IN_DIR)).unwrap();
+        std::fs::write(
+            dir.join("config.json"),
+            r#"{ "include": ["teams/*.yaml"], "mcpServers": { "a": { "command": "a", "args": [] } } }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("teams/b.yaml"),
+            "mcpServers:\n  b: { command: b, args: [] }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join(DROP_IN_DIR).join("c.toml"),
+            "[mcpServers.c]\ncommand = \"c\"\nargs = []\n",
+        )
+        .unwrap();
+
+        let config = Config::read(dir.join("config.json"), None).unwrap();
+        let mut names = config
+            .servers
+            .keys()
+            .map(|n| n.to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["a", "b", "c"]);
+        let canonical = std::fs::canonicalize(&dir).unwrap();
+        let watched = config.watch_paths();
+        assert_eq!(watched.len(), 5);
+        assert!(watched.contains(&(canonical.join(DROP_IN_DIR), false)));
+        assert!(watched.contains(&(canonical.join("teams"), false)));
+        assert_eq!(
+            glob_base(Path::new("/etc/mcpgate/teams/**/*.json")),
+            (PathBuf::from("/etc/mcpgate/teams"), true)
+        );
+
+        std::fs::write(
+            dir.join(DROP_IN_DIR).join("d.json"),
+            r#"{ "mcpServers": { "b": { "command": "b", "args": [] } } }"#,
+        )
+        .unwrap();
+        let err = Config::read(dir.join("config.json"), None).unwrap_err();
+        assert!(err.to_string().starts_with("server `b` is defined in both"));
+        std::fs::remove_file(dir.join(DROP_IN_DIR).join("d.json")).unwrap();
+
+        // The main file's strictness applies to included files.
+        std::fs::write(
+            dir.join("config.json"),
+            r#"{ "include": ["teams/*.yaml"], "strictVariables": true, "mcpServers": {} }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("teams/e.yaml"),
+            "mcpServers:\n  e: { command: \"${MCPGATE_TEST_UNSET}\", args: [] }\n",
+        )
+        .unwrap();
+        let err = Config::read(dir.join("config.json"), None).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("undefined variable MCPGATE_TEST_UNSET"),
+            "{err}"
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let path = std::env::temp_dir().join(format!("mcpgate-errors-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            "{\n  \"mcpServers\": {\n    \"fetch\": { \"command\": \"uvx\", \"args\": [1] }\n  }\n}\n",
+        )
+        .unwrap();
+
+        let err = Config::read(&path, None).unwrap_err().to_string();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.contains("(near line 3, column 43)"), "{err}");
+        assert!(
+            err.ends_with(
+                "mcpServers.fetch: args[0]: invalid type: integer `1`, expected a string"
+            ),
+            "{err}"
+        );
+
+        let path = [
+            PathKey::Field("mcpServers".into()),
+            PathKey::Field("fetch".into()),
+            PathKey::Field("args".into()),
+            PathKey::Index(0),
+        ];
+        let yaml = "mcpServers:\n  fetch:\n    command: uvx\n    args:\n      - 1\n";
+        assert_eq!(ConfigFormat::Yaml.locate(yaml, &path), Some((5, 9)));
+        let toml = "[mcpServers.fetch]\ncommand = \"uvx\"\nargs = [1]\n";
+        assert_eq!(ConfigFormat::Toml.locate(toml, &path), Some((3, 9)));
+
+        let err =
+            serde_json::from_str::<McpServerConfig>(r#"{ "type": "websocket" }"#).unwrap_err();
+        assert!(err.to_string().starts_with("unknown variant `websocket`"));
+    }
+
+    #[cfg(feature = "schemars")]
+    #[test]
+    fn test_schema() {
+        let schema = serde_json::to_value(Config::schema()).unwrap();
+        let server = &schema["definitions"]["McpServerConfig"]["oneOf"];
+        let types = server
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["allOf"][1]["properties"]["type"]["enum"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            [
+                serde_json::json!(["stdio"]),
+                serde_json::json!(["sse"]),
+                serde_json::json!(["streamableHttp", "streamable", "http"]),
+            ]
+        );
+        assert!(
+            schema["definitions"]["McpStdioConfig"]["properties"]
+                .get("gracePeriod")
+                .is_some()
+        );
+    }
 }
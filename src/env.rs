@@ -0,0 +1,147 @@
+use std::{collections::HashMap, io, path::Path};
+
+use tokio::process::Command;
+
+use crate::config::{EnvValue, InheritEnv};
+
+/// Sets the environment of a stdio server: the inherited gateway variables, then those
+/// of the dotenv file, then the configured ones, passed through `expand`.
+pub fn apply(
+    cmd: &mut Command,
+    inherit: &InheritEnv,
+    env_file: Option<&Path>,
+    env: Option<&HashMap<String, EnvValue>>,
+    expand: impl Fn(&str) -> String,
+) -> io::Result<()> {
+    match inherit {
+        InheritEnv::All => (),
+        InheritEnv::None => {
+            cmd.env_clear();
+        }
+        InheritEnv::Allowlist(names) => {
+            cmd.env_clear();
+            for name in names {
+                if let Some(value) = std::env::var_os(name) {
+                    cmd.env(name, value);
+                }
+            }
+        }
+    }
+
+    if let Some(path) = env_file {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            io::Error::new(e.kind(), format!("failed to read {}: {e}", path.display()))
+        })?;
+        let vars = parse_dotenv(&content).map_err(|e| {
+            io::Error::new(e.kind(), format!("failed to parse {}: {e}", path.display()))
+        })?;
+        cmd.envs(vars);
+    }
+
+    for (name, value) in env.into_iter().flatten() {
+        cmd.env(name, expand(&resolve(name, value)?));
+    }
+    Ok(())
+}
+
+fn resolve(name: &str, value: &EnvValue) -> io::Result<String> {
+    match value {
+        EnvValue::Value(value) => Ok(value.clone()),
+        EnvValue::File { file } => std::fs::read_to_string(file)
+            .map(|value| value.trim_end_matches(['\r', '\n']).to_string())
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("failed to read {name} from {}: {e}", file.display()),
+                )
+            }),
+        EnvValue::Env { env } => std::env::var(env).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("failed to read {name}: environment variable {env} is not set"),
+            )
+        }),
+    }
+}
+
+/// Parses `KEY=value` lines, skipping blank lines and `#` comments.
+pub fn parse_dotenv(content: &str) -> io::Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: expected KEY=value", n + 1),
+            ));
+        };
+        let value = value.trim();
+        let value = if let Some(value) = quoted(value, '"') {
+            value
+                .replace("\\n", "\n")
+                .replace("\\\"", "\"")
+                .replace("\\\\", "\\")
+        } else if let Some(value) = quoted(value, '\'') {
+            value.to_string()
+        } else {
+            value
+                .split_once(" #")
+                .map_or(value, |(value, _)| value)
+                .trim_end()
+                .to_string()
+        };
+        vars.push((key.trim().to_string(), value));
+    }
+    Ok(vars)
+}
+
+fn quoted(value: &str, quote: char) -> Option<&str> {
+    value.strip_prefix(quote)?.strip_suffix(quote)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dotenv() {
+        let vars = parse_dotenv(
+            r#"
+            # comment
+            TOKEN=abc # trailing
+            export REGION = eu
+            GREETING="hello\nworld"
+            RAW='a # b'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            vars,
+            [
+                ("TOKEN".to_string(), "abc".to_string()),
+                ("REGION".to_string(), "eu".to_string()),
+                ("GREETING".to_string(), "hello\nworld".to_string()),
+                ("RAW".to_string(), "a # b".to_string()),
+            ]
+        );
+        assert!(parse_dotenv("NOT A VAR").is_err());
+    }
+
+    #[test]
+    fn test_env_values_are_redacted() {
+        let env = serde_json::from_str::<HashMap<String, EnvValue>>(
+            r#"{ "TOKEN": "s3cret", "KEY": { "file": "/run/secrets/key" }, "HOME": { "env": "HOME" } }"#,
+        )
+        .unwrap();
+
+        let json = serde_json::to_value(&env).unwrap();
+        assert_eq!(json["TOKEN"], "<redacted>");
+        assert_eq!(json["KEY"]["file"], "/run/secrets/key");
+        assert_eq!(json["HOME"]["env"], "HOME");
+        assert!(!format!("{env:?}").contains("s3cret"));
+    }
+}
@@ -0,0 +1,148 @@
+use std::{collections::HashMap, str::FromStr};
+
+use serde_json::{Map, Value, json};
+
+/// Client whose config shape the discovery endpoint serves, besides the gateway's own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Claude Desktop, which only runs stdio servers, so every server is wrapped in
+    /// `mcp-remote`.
+    Claude,
+    Vscode,
+    Cursor,
+    /// The `mcpServers` block of a Continue `config.yaml`.
+    Continue,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "claude" => Self::Claude,
+            "vscode" => Self::Vscode,
+            "cursor" => Self::Cursor,
+            "continue" => Self::Continue,
+            _ => {
+                return Err(format!(
+                    "unknown format `{s}`, expected one of claude, vscode, cursor, continue, raw"
+                ));
+            }
+        })
+    }
+}
+
+/// A server as reached through the gateway.
+#[derive(Debug)]
+pub struct Endpoint<'a> {
+    pub name: &'a str,
+    pub url: String,
+    pub sse: bool,
+}
+
+/// Builds the config snippet of a client, sending `headers` with every request.
+pub fn snippet(format: Format, endpoints: &[Endpoint], headers: &HashMap<String, String>) -> Value {
+    let servers = endpoints.iter().map(|e| {
+        let server = match format {
+            Format::Claude => mcp_remote(e, headers),
+            Format::Vscode => with_headers(
+                json!({ "type": if e.sse { "sse" } else { "http" }, "url": e.url }),
+                headers,
+            ),
+            Format::Cursor => with_headers(json!({ "url": e.url }), headers),
+            Format::Continue => {
+                let mut server = json!({
+                    "name": e.name,
+                    "type": if e.sse { "sse" } else { "streamable-http" },
+                    "url": e.url,
+                });
+                if !headers.is_empty() {
+                    server["requestOptions"] = json!({ "headers": headers });
+                }
+                server
+            }
+        };
+        (e.name.to_string(), server)
+    });
+
+    match format {
+        Format::Vscode => json!({ "servers": servers.collect::<Map<_, _>>() }),
+        Format::Continue => json!({ "mcpServers": servers.map(|(_, s)| s).collect::<Vec<_>>() }),
+        _ => json!({ "mcpServers": servers.collect::<Map<_, _>>() }),
+    }
+}
+
+fn with_headers(mut server: Value, headers: &HashMap<String, String>) -> Value {
+    if !headers.is_empty() {
+        server["headers"] = json!(headers);
+    }
+    server
+}
+
+/// Runs the endpoint through `npx mcp-remote` for clients limited to stdio.
+fn mcp_remote(endpoint: &Endpoint, headers: &HashMap<String, String>) -> Value {
+    let transport = if endpoint.sse {
+        "sse-only"
+    } else {
+        "http-only"
+    };
+    let mut args = ["-y", "mcp-remote", &endpoint.url, "--transport", transport]
+        .map(String::from)
+        .to_vec();
+    let mut headers = headers.iter().collect::<Vec<_>>();
+    headers.sort();
+    for (name, value) in headers {
+        args.extend(["--header".to_string(), format!("{name}: {value}")]);
+    }
+    json!({ "command": "npx", "args": args })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snippet() {
+        let endpoints = [
+            Endpoint {
+                name: "fetch",
+                url: "http://gw/fetch".to_string(),
+                sse: false,
+            },
+            Endpoint {
+                name: "time",
+                url: "http://gw/time/sse".to_string(),
+                sse: true,
+            },
+        ];
+        let headers = HashMap::from([("Authorization".to_string(), "Bearer t".to_string())]);
+
+        assert_eq!(
+            snippet(Format::Vscode, &endpoints, &HashMap::new()),
+            json!({ "servers": {
+                "fetch": { "type": "http", "url": "http://gw/fetch" },
+                "time": { "type": "sse", "url": "http://gw/time/sse" },
+            }})
+        );
+        assert_eq!(
+            snippet(Format::Claude, &endpoints[..1], &headers),
+            json!({ "mcpServers": { "fetch": {
+                "command": "npx",
+                "args": [
+                    "-y", "mcp-remote", "http://gw/fetch", "--transport", "http-only",
+                    "--header", "Authorization: Bearer t",
+                ],
+            }}})
+        );
+        assert_eq!(
+            snippet(Format::Continue, &endpoints[1..], &headers)["mcpServers"][0],
+            json!({
+                "name": "time",
+                "type": "sse",
+                "url": "http://gw/time/sse",
+                "requestOptions": { "headers": { "Authorization": "Bearer t" } },
+            })
+        );
+        assert!("claude-code".parse::<Format>().is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use std::{net::IpAddr, str::FromStr};
+
+use http::{HeaderMap, header};
+
+/// URL the gateway is reached at from outside, such as `https://example.com/gateway`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BaseUrl {
+    pub scheme: String,
+    pub host: String,
+    /// Path prefix without the trailing `/`, empty at the root.
+    pub prefix: String,
+}
+
+impl BaseUrl {
+    /// Appends a path starting with `/`.
+    pub fn join(&self, path: &str) -> String {
+        format!("{}://{}{}{path}", self.scheme, self.host, self.prefix)
+    }
+}
+
+impl FromStr for BaseUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let uri = s
+            .parse::<http::Uri>()
+            .map_err(|e| format!("invalid URL {s}: {e}"))?;
+        let (Some(scheme), Some(host)) = (uri.scheme_str(), uri.authority()) else {
+            return Err(format!("{s} is not an absolute URL"));
+        };
+        Ok(Self {
+            scheme: scheme.to_string(),
+            host: host.to_string(),
+            prefix: uri.path().trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+/// Address or range, such as `10.0.0.0/8`, of proxies whose forwarding headers are trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrustedProxy {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl TrustedProxy {
+    fn contains(&self, ip: IpAddr) -> bool {
+        // Shifting every bit out, as a zero length range does, leaves nothing to compare.
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let shift = 32 - self.prefix_len;
+                u32::from(net).checked_shr(shift).unwrap_or(0)
+                    == u32::from(ip).checked_shr(shift).unwrap_or(0)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let shift = 128 - self.prefix_len;
+                u128::from(net).checked_shr(shift).unwrap_or(0)
+                    == u128::from(ip).checked_shr(shift).unwrap_or(0)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|e| format!("invalid address {s}: {e}"))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match len {
+            Some(len) => len
+                .parse::<u32>()
+                .ok()
+                .filter(|len| *len <= bits)
+                .ok_or_else(|| format!("invalid prefix length in {s}"))?,
+            None => bits,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Base URL clients should use: the configured public URL, else the `Host` header as
+/// amended by the `Forwarded` and `X-Forwarded-*` headers of a trusted proxy, followed by
+/// the `base_path` the gateway serves under.
+pub fn base_url(
+    public: Option<&BaseUrl>,
+    trusted: &[TrustedProxy],
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    base_path: &str,
+) -> BaseUrl {
+    if let Some(public) = public {
+        return public.clone();
+    }
+
+    let mut base = BaseUrl {
+        scheme: "http".to_string(),
+        host: last(headers, header::HOST.as_str()).unwrap_or_default(),
+        prefix: base_path.to_string(),
+    };
+    if !peer.is_some_and(|peer| trusted.iter().any(|t| t.contains(peer))) {
+        return base;
+    }
+
+    // Only the last element is the trusted proxy's; those before it came from the client.
+    let forwarded = headers
+        .get_all(header::FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(parse_forwarded)
+        .last()
+        .unwrap_or_default();
+    let forwarded = |name: &str| {
+        forwarded
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    };
+    if let Some(proto) = forwarded("proto").or_else(|| last(headers, "x-forwarded-proto")) {
+        base.scheme = proto;
+    }
+    if let Some(host) = forwarded("host").or_else(|| last(headers, "x-forwarded-host")) {
+        base.host = host;
+    }
+    if let Some(prefix) = last(headers, "x-forwarded-prefix") {
+        let prefix = prefix.trim_matches('/');
+        if !prefix.is_empty() {
+            base.prefix = format!("/{prefix}{base_path}");
+        }
+    }
+    base
+}
+
+/// Last element of a comma-separated header, as added by the proxy nearest the gateway.
+fn last(headers: &HeaderMap, name: &str) -> Option<String> {
+    let value = headers.get_all(name).iter().next_back()?.to_str().ok()?;
+    let value = value.rsplit(',').next()?.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Splits a `Forwarded` header into its elements, each a list of parameters with their
+/// values unquoted, keeping the separators inside quoted strings.
+fn parse_forwarded(value: &str) -> Vec<Vec<(String, String)>> {
+    let mut elements = vec![Vec::new()];
+    let mut chars = value.chars().peekable();
+    while chars.peek().is_some() {
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|c| !matches!(c, '=' | ';' | ',')) {
+            name.push(c);
+        }
+        let mut value = String::new();
+        if chars.next_if_eq(&'=').is_some() {
+            if chars.next_if_eq(&'"').is_some() {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => value.extend(chars.next()),
+                        c => value.push(c),
+                    }
+                }
+            }
+            while let Some(c) = chars.next_if(|c| !matches!(c, ';' | ',')) {
+                value.push(c);
+            }
+        }
+        let name = name.trim();
+        if !name.is_empty() {
+            let pair = (name.to_string(), value.trim().to_string());
+            elements.last_mut().unwrap().push(pair);
+        }
+        if chars.next() == Some(',') {
+            elements.push(Vec::new());
+        }
+    }
+    elements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base_url() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "10.0.0.5:8051".parse().unwrap());
+        // The client sends its own element first, and the proxy appends the real one.
+        headers.insert(
+            header::FORWARDED,
+            "for=6.6.6.6;proto=http;host=\"evil.example.com, for=1.2.3.4;host=evil\""
+                .parse()
+                .unwrap(),
+        );
+        headers.append(
+            header::FORWARDED,
+            "for=1.2.3.4;proto=https;host=\"mcp.example.com\""
+                .parse()
+                .unwrap(),
+        );
+        headers.insert("x-forwarded-host", "ignored.example.com".parse().unwrap());
+        headers.insert("x-forwarded-prefix", "/gateway/".parse().unwrap());
+
+        let trusted = ["10.0.0.0/8".parse::<TrustedProxy>().unwrap()];
+        let proxy = "::ffff:10.1.2.3".parse().ok();
+        let base = base_url(None, &trusted, proxy, &headers, "/mcpgate");
+        assert_eq!(
+            base.join("/fetch"),
+            "https://mcp.example.com/gateway/mcpgate/fetch"
+        );
+
+        let mut spoofed = HeaderMap::new();
+        spoofed.insert(header::HOST, "10.0.0.5:8051".parse().unwrap());
+        spoofed.insert("x-forwarded-proto", "http, https".parse().unwrap());
+        spoofed.insert(
+            "x-forwarded-host",
+            "evil.example.com, mcp.example.com".parse().unwrap(),
+        );
+        spoofed.insert("x-forwarded-prefix", "/evil, /gateway".parse().unwrap());
+        let base = base_url(None, &trusted, proxy, &spoofed, "");
+        assert_eq!(base.join("/fetch"), "https://mcp.example.com/gateway/fetch");
+
+        assert_eq!(
+            parse_forwarded(r#"for="[::1]:80";host="a\"b,c", for=1.2.3.4"#),
+            [
+                vec![
+                    ("for".to_string(), "[::1]:80".to_string()),
+                    ("host".to_string(), "a\"b,c".to_string()),
+                ],
+                vec![("for".to_string(), "1.2.3.4".to_string())],
+            ]
+        );
+
+        let stranger = "192.168.1.1".parse().ok();
+        let base = base_url(None, &trusted, stranger, &headers, "");
+        assert_eq!(base.join("/fetch"), "http://10.0.0.5:8051/fetch");
+
+        let public = "https://public.example.com/mcp/"
+            .parse::<BaseUrl>()
+            .unwrap();
+        let base = base_url(Some(&public), &trusted, proxy, &headers, "/mcpgate");
+        assert_eq!(base.join("/fetch"), "https://public.example.com/mcp/fetch");
+
+        assert!(
+            "0.0.0.0/0"
+                .parse::<TrustedProxy>()
+                .unwrap()
+                .contains(stranger.unwrap())
+        );
+        assert!("10.0.0.0/33".parse::<TrustedProxy>().is_err());
+    }
+}
@@ -1,32 +1,183 @@
-use std::sync::Arc;
+use std::{
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
 use rmcp::Error as McpError;
 use rmcp::{
-    RoleClient, RoleServer, Service, ServiceError,
+    RoleServer, Service, ServiceError,
     model::{
-        ClientNotification, ClientRequest, ErrorCode, InitializeRequestParam, ListPromptsResult,
-        ListResourceTemplatesResult, ListResourcesResult, ListToolsResult, ServerInfo,
-        ServerResult,
+        ClientInfo, ClientNotification, ClientRequest, ErrorCode, ListPromptsResult,
+        ListResourceTemplatesResult, ListResourcesResult, ListToolsResult, ProtocolVersion,
+        ServerInfo, ServerResult,
     },
-    service::{RequestContext, RunningService, ServiceRole},
+    service::{RequestContext, ServiceRole},
 };
-use tokio::sync::RwLock;
+use tokio::{sync::RwLock, time::Instant};
+use tokio_util::sync::CancellationToken;
 
-use crate::config::McpServerConfig;
+use crate::config::Pagination;
 use crate::error::Error;
+use crate::protocol::ProtocolBridge;
+use crate::server::McpServer;
+use crate::upstream::UpstreamClient;
+
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(0);
+
+type ClientSlot = RwLock<Option<Arc<UpstreamClient>>>;
 
 #[derive(Debug)]
 pub struct Gate {
-    config: Arc<McpServerConfig>,
-    client: RwLock<Option<Arc<RunningService<RoleClient, InitializeRequestParam>>>>,
+    server: Arc<McpServer>,
+    session: u64,
+    ct: CancellationToken,
+    client_info: RwLock<Option<ClientInfo>>,
+    client: Arc<ClientSlot>,
+    last_active: Arc<Mutex<Instant>>,
+    protocol: RwLock<ProtocolBridge>,
 }
 
 impl Gate {
-    pub fn new(config: Arc<McpServerConfig>) -> Self {
-        Self {
-            config,
+    pub fn new(server: Arc<McpServer>, ct: CancellationToken) -> Self {
+        let gate = Self {
+            server,
+            session: NEXT_SESSION.fetch_add(1, Ordering::Relaxed),
+            ct,
+            client_info: Default::default(),
             client: Default::default(),
+            last_active: Arc::new(Mutex::new(Instant::now())),
+            protocol: Default::default(),
+        };
+        gate.spawn_reaper();
+        gate
+    }
+
+    /// Returns the upstream client, starting a new one if it was released while idle.
+    async fn client(&self) -> Result<Arc<UpstreamClient>, McpError> {
+        if let Some(client) = self.client.read().await.as_ref() {
+            return Ok(client.clone());
+        }
+
+        let mut client = self.client.write().await;
+        if let Some(client) = client.as_ref() {
+            return Ok(client.clone());
+        }
+        let Some(client_info) = self.client_info.read().await.clone() else {
+            return Err(McpError::new(
+                ErrorCode::INVALID_REQUEST,
+                "session is not initialized",
+                None,
+            ));
+        };
+        tracing::info!(session = self.session, "cold starting upstream");
+        let new_client = self.server.connect(Some(client_info), self.session).await?;
+        *client = Some(new_client.clone());
+        Ok(new_client)
+    }
+
+    /// Releases the upstream of an idle session and closes the session once it
+    /// outlives its maximum lifetime.
+    fn spawn_reaper(&self) {
+        let options = self.server.config().options();
+        let idle_timeout = options.idle_timeout();
+        let deadline = options.max_session_lifetime().map(|d| Instant::now() + d);
+        if idle_timeout.is_none() && deadline.is_none() {
+            return;
         }
+
+        let session = self.session;
+        tokio::spawn(reap(
+            session,
+            Arc::downgrade(&self.client),
+            self.last_active.clone(),
+            self.ct.clone(),
+            idle_timeout,
+            deadline,
+            move |client: Arc<UpstreamClient>| async move {
+                client
+                    .service()
+                    .subscriptions()
+                    .contains_session(session)
+                    .await
+            },
+        ));
+    }
+}
+
+/// Empties `slot` once the session has been idle for `idle_timeout`, unless `in_use` says
+/// its client is still needed, and cancels the session at `deadline`. Returns as soon as
+/// the session is gone.
+async fn reap<C, F, Fut>(
+    session: u64,
+    slot: Weak<RwLock<Option<C>>>,
+    last_active: Arc<Mutex<Instant>>,
+    ct: CancellationToken,
+    idle_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    in_use: F,
+) where
+    C: Clone,
+    F: Fn(C) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let mut next_idle_check = idle_timeout.map(|t| Instant::now() + t);
+    while let Some(wake) = next_idle_check.into_iter().chain(deadline).min() {
+        tokio::select! {
+            _ = ct.cancelled() => break,
+            _ = tokio::time::sleep_until(wake) => (),
+        }
+
+        let Some(slot) = slot.upgrade() else {
+            break;
+        };
+
+        if deadline.is_some_and(|d| d <= Instant::now()) {
+            tracing::info!(session, "session reached its max lifetime, closing");
+            ct.cancel();
+            break;
+        }
+
+        let Some(idle_timeout) = idle_timeout else {
+            continue;
+        };
+        let last_active = *last_active.lock().unwrap();
+        if last_active.elapsed() < idle_timeout {
+            next_idle_check = Some(last_active + idle_timeout);
+            continue;
+        }
+        next_idle_check = Some(Instant::now() + idle_timeout);
+
+        let mut client = slot.write().await;
+        let subscribed = match client.as_ref() {
+            Some(c) => in_use(c.clone()).await,
+            None => continue,
+        };
+        if !subscribed {
+            tracing::info!(session, "session idle, releasing upstream");
+            *client = None;
+        }
+    }
+}
+
+impl Drop for Gate {
+    fn drop(&mut self) {
+        let Some(client) = self.client.try_write().ok().and_then(|mut c| c.take()) else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let session = self.session;
+        runtime.spawn(async move {
+            client
+                .service()
+                .subscriptions()
+                .leave(client.peer(), session)
+                .await;
+        });
     }
 }
 
@@ -36,99 +187,185 @@ impl Service<RoleServer> for Gate {
         request: <RoleServer as ServiceRole>::PeerReq,
         ctx: RequestContext<RoleServer>,
     ) -> Result<<RoleServer as ServiceRole>::Resp, McpError> {
+        *self.last_active.lock().unwrap() = Instant::now();
         match request {
-            ClientRequest::InitializeRequest(_) => {
-                let client_info = ctx.peer.peer_info().cloned();
-                let client = self.config.create_client(client_info).await?;
+            ClientRequest::InitializeRequest(request) => {
+                let mut client_info = request.params;
+                let requested = client_info.protocol_version;
+                client_info.protocol_version = ProtocolVersion::LATEST;
+                *(self.client_info.write().await) = Some(client_info.clone());
+
+                let client = self.server.connect(Some(client_info), self.session).await?;
                 *(self.client.write().await) = Some(client.clone());
 
                 let res = client.peer_info().cloned().unwrap_or_default();
+                let protocol = ProtocolBridge::negotiate(requested, res.protocol_version.clone());
+                tracing::debug!(
+                    downstream = %protocol.downstream(),
+                    upstream = %protocol.upstream(),
+                    "protocol negotiated"
+                );
+                let res = protocol.server_info(res);
+                *(self.protocol.write().await) = protocol;
 
                 Ok(ServerResult::InitializeResult(res))
             }
             ClientRequest::PingRequest(_) => Ok(ServerResult::empty(())),
             ClientRequest::CompleteRequest(request) => {
-                let client = self.client.read().await.as_ref().unwrap().clone();
+                let client = self.client().await?;
 
-                let res = client.complete(request.params).await.map_err(mcp_err)?;
+                let res = client
+                    .complete(request.params)
+                    .await
+                    .map_err(mcp_err(&client))?;
 
                 Ok(ServerResult::CompleteResult(res))
             }
             ClientRequest::SetLevelRequest(request) => {
-                let client = self.client.read().await.as_ref().unwrap().clone();
+                let client = self.client().await?;
 
-                client.set_level(request.params).await.map_err(mcp_err)?;
+                client
+                    .set_level(request.params)
+                    .await
+                    .map_err(mcp_err(&client))?;
 
                 Ok(ServerResult::empty(()))
             }
             ClientRequest::GetPromptRequest(request) => {
-                let client = self.client.read().await.as_ref().unwrap().clone();
-                let res = client.get_prompt(request.params).await.map_err(mcp_err)?;
+                let client = self.client().await?;
+                let res = client
+                    .get_prompt(request.params)
+                    .await
+                    .map_err(mcp_err(&client))?;
                 Ok(ServerResult::GetPromptResult(res))
             }
-            ClientRequest::ListPromptsRequest(_) => {
-                let client = self.client.read().await.as_ref().unwrap().clone();
+            ClientRequest::ListPromptsRequest(request) => {
+                let client = self.client().await?;
+                let cursor = request.params.as_ref().and_then(|p| p.cursor.clone());
 
-                let prompts = client.list_all_prompts().await.map_err(mcp_err)?;
+                let res = self
+                    .server
+                    .cache()
+                    .prompts(cursor, async {
+                        match self.server.config().options().pagination() {
+                            Pagination::Passthrough => client.list_prompts(request.params).await,
+                            Pagination::Buffered => Ok(ListPromptsResult {
+                                next_cursor: None,
+                                prompts: client.list_all_prompts().await?,
+                            }),
+                        }
+                    })
+                    .await
+                    .map_err(mcp_err(&client))?;
 
-                Ok(ServerResult::ListPromptsResult(ListPromptsResult {
-                    next_cursor: None,
-                    prompts,
-                }))
+                Ok(ServerResult::ListPromptsResult(res))
             }
-            ClientRequest::ListResourcesRequest(_) => {
-                let client = self.client.read().await.as_ref().unwrap().clone();
-                let resources = client.list_all_resources().await.map_err(mcp_err)?;
-                Ok(ServerResult::ListResourcesResult(ListResourcesResult {
-                    next_cursor: None,
-                    resources,
-                }))
+            ClientRequest::ListResourcesRequest(request) => {
+                let client = self.client().await?;
+                let cursor = request.params.as_ref().and_then(|p| p.cursor.clone());
+                let res = self
+                    .server
+                    .cache()
+                    .resources(cursor, async {
+                        match self.server.config().options().pagination() {
+                            Pagination::Passthrough => client.list_resources(request.params).await,
+                            Pagination::Buffered => Ok(ListResourcesResult {
+                                next_cursor: None,
+                                resources: client.list_all_resources().await?,
+                            }),
+                        }
+                    })
+                    .await
+                    .map_err(mcp_err(&client))?;
+                Ok(ServerResult::ListResourcesResult(res))
             }
-            ClientRequest::ListResourceTemplatesRequest(_) => {
-                let client = self.client.read().await.as_ref().unwrap().clone();
+            ClientRequest::ListResourceTemplatesRequest(request) => {
+                let client = self.client().await?;
+                let cursor = request.params.as_ref().and_then(|p| p.cursor.clone());
 
-                let resource_templates = client
-                    .list_all_resource_templates()
+                let res = self
+                    .server
+                    .cache()
+                    .resource_templates(cursor, async {
+                        match self.server.config().options().pagination() {
+                            Pagination::Passthrough => {
+                                client.list_resource_templates(request.params).await
+                            }
+                            Pagination::Buffered => Ok(ListResourceTemplatesResult {
+                                next_cursor: None,
+                                resource_templates: client.list_all_resource_templates().await?,
+                            }),
+                        }
+                    })
                     .await
-                    .map_err(mcp_err)?;
-
-                Ok(ServerResult::ListResourceTemplatesResult(
-                    ListResourceTemplatesResult {
-                        next_cursor: None,
-                        resource_templates,
-                    },
-                ))
+                    .map_err(mcp_err(&client))?;
+
+                Ok(ServerResult::ListResourceTemplatesResult(res))
             }
             ClientRequest::ReadResourceRequest(request) => {
-                let client = self.client.read().await.as_ref().unwrap().clone();
+                let client = self.client().await?;
+                let uri = request.params.uri.clone();
 
-                let res = client
-                    .read_resource(request.params)
+                let res = self
+                    .server
+                    .cache()
+                    .read(&uri, client.read_resource(request.params))
                     .await
-                    .map_err(mcp_err)?;
+                    .map_err(mcp_err(&client))?;
                 Ok(ServerResult::ReadResourceResult(res))
             }
             ClientRequest::SubscribeRequest(request) => {
-                let client = self.client.read().await.as_ref().unwrap().clone();
-                client.subscribe(request.params).await.map_err(mcp_err)?;
+                let client = self.client().await?;
+                client
+                    .service()
+                    .subscriptions()
+                    .subscribe(client.peer(), self.session, ctx.peer, request.params)
+                    .await
+                    .map_err(mcp_err(&client))?;
                 Ok(ServerResult::empty(()))
             }
             ClientRequest::UnsubscribeRequest(request) => {
-                let client = self.client.read().await.as_ref().unwrap().clone();
-                client.unsubscribe(request.params).await.map_err(mcp_err)?;
+                let client = self.client().await?;
+                client
+                    .service()
+                    .subscriptions()
+                    .unsubscribe(client.peer(), self.session, request.params)
+                    .await
+                    .map_err(mcp_err(&client))?;
                 Ok(ServerResult::empty(()))
             }
             ClientRequest::CallToolRequest(request) => {
-                let client = self.client.read().await.as_ref().unwrap().clone();
-                let res = client.call_tool(request.params).await.map_err(mcp_err)?;
+                let client = self.client().await?;
+                let res = self
+                    .server
+                    .cache()
+                    .call(&request.params, client.call_tool(request.params.clone()))
+                    .await
+                    .map_err(mcp_err(&client))?;
+                let res = self.protocol.read().await.call_tool_result(res);
                 Ok(ServerResult::CallToolResult(res))
             }
-            ClientRequest::ListToolsRequest(_) => {
-                let client = self.client.read().await.as_ref().unwrap().clone();
-                let tools = client.list_all_tools().await.map_err(mcp_err)?;
+            ClientRequest::ListToolsRequest(request) => {
+                let client = self.client().await?;
+                let cursor = request.params.as_ref().and_then(|p| p.cursor.clone());
+                let res = self
+                    .server
+                    .cache()
+                    .tools(cursor, async {
+                        match self.server.config().options().pagination() {
+                            Pagination::Passthrough => client.list_tools(request.params).await,
+                            Pagination::Buffered => Ok(ListToolsResult {
+                                next_cursor: None,
+                                tools: client.list_all_tools().await?,
+                            }),
+                        }
+                    })
+                    .await
+                    .map_err(mcp_err(&client))?;
+                let tools = self.protocol.read().await.tools(res.tools);
                 Ok(ServerResult::ListToolsResult(ListToolsResult {
-                    next_cursor: None,
                     tools,
+                    ..res
                 }))
             }
         }
@@ -151,8 +388,15 @@ impl Service<RoleServer> for Gate {
     }
 }
 
-fn mcp_err(err: ServiceError) -> McpError {
-    McpError::new(ErrorCode::INTERNAL_ERROR, err.to_string(), None)
+/// Maps an upstream failure, explaining it with the exit of the upstream process if it died.
+fn mcp_err(client: &UpstreamClient) -> impl Fn(ServiceError) -> McpError + '_ {
+    |err| {
+        let message = match client.service().exit().get() {
+            Some(exit) => format!("{err}: {exit}"),
+            None => err.to_string(),
+        };
+        McpError::new(ErrorCode::INTERNAL_ERROR, message, None)
+    }
 }
 
 impl From<Error> for McpError {
@@ -160,3 +404,65 @@ impl From<Error> for McpError {
         McpError::new(ErrorCode::INTERNAL_ERROR, err.to_string(), None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reap_idle() {
+        let slot = Arc::new(RwLock::new(Some(())));
+        let last_active = Arc::new(Mutex::new(Instant::now()));
+        let ct = CancellationToken::new();
+        tokio::spawn(reap(
+            0,
+            Arc::downgrade(&slot),
+            last_active.clone(),
+            ct.clone(),
+            Some(Duration::from_secs(10)),
+            None,
+            |_| async { false },
+        ));
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        *last_active.lock().unwrap() = Instant::now();
+        tokio::time::sleep(Duration::from_secs(9)).await;
+        assert!(slot.read().await.is_some());
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(slot.read().await.is_none());
+        assert!(!ct.is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reap_lifetime() {
+        let lifetime = Duration::from_secs(60);
+        let spawn = |slot: &Arc<RwLock<Option<()>>>, ct: &CancellationToken| {
+            tokio::spawn(reap(
+                0,
+                Arc::downgrade(slot),
+                Arc::new(Mutex::new(Instant::now())),
+                ct.clone(),
+                None,
+                Some(Instant::now() + lifetime),
+                |_| async { false },
+            ))
+        };
+
+        let slot = Arc::new(RwLock::new(None));
+        let ct = CancellationToken::new();
+        let reaper = spawn(&slot, &ct);
+        tokio::time::sleep(lifetime / 2).await;
+        assert!(!ct.is_cancelled());
+        reaper.await.unwrap();
+        assert!(ct.is_cancelled());
+
+        // A session that closed before its deadline is left alone.
+        let slot = Arc::new(RwLock::new(None));
+        let ct = CancellationToken::new();
+        let reaper = spawn(&slot, &ct);
+        drop(slot);
+        reaper.await.unwrap();
+        assert!(!ct.is_cancelled());
+    }
+}
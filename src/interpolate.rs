@@ -0,0 +1,197 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::bail;
+use serde_json::Value;
+
+use crate::workdir::SESSION_DIR;
+
+/// Server fields whose strings are interpolated.
+const FIELDS: [&str; 7] = ["url", "command", "args", "cwd", "env", "envFile", "headers"];
+
+/// Expands `${VAR}` and `${VAR:-default}` in the servers of a raw config.
+///
+/// Variables are looked up among the built-ins `CONFIG_DIR` and `SERVER_NAME`, then in the
+/// gateway environment. `$${VAR}` keeps a literal `${VAR}`, and `${SESSION_DIR}` is left
+/// for the session to fill in.
+///
+/// The VS Code and Cursor forms `${input:ID}`, `${env:VAR}`, `${workspaceFolder}` and
+/// `${userHome}` are understood as well, inputs being taken from `inputs`.
+///
+/// Undefined variables expand to an empty string, or fail when `strict`. An input without
+/// a value always fails.
+pub fn config(
+    config: &mut Value,
+    config_dir: &Path,
+    inputs: &HashMap<String, String>,
+    strict: bool,
+) -> anyhow::Result<()> {
+    // Editor configs live in `.vscode` or `.cursor` at the root of the workspace.
+    let workspace = match config_dir.file_name().and_then(|n| n.to_str()) {
+        Some(".vscode" | ".cursor") => config_dir.parent().unwrap_or(config_dir),
+        _ => config_dir,
+    };
+    let workspace = workspace.to_string_lossy();
+    let config_dir = config_dir.to_string_lossy();
+
+    for key in ["mcpServers", "servers"] {
+        let Some(Value::Object(servers)) = config.get_mut(key) else {
+            continue;
+        };
+        for (name, server) in servers.iter_mut() {
+            let Value::Object(server) = server else {
+                continue;
+            };
+            let vars = Vars {
+                config_dir: &config_dir,
+                workspace: &workspace,
+                server_name: name,
+                inputs,
+                strict,
+            };
+            for field in FIELDS {
+                if let Some(value) = server.get_mut(field) {
+                    vars.walk(value, &format!("{key}.{name}.{field}"))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Vars<'a> {
+    config_dir: &'a str,
+    workspace: &'a str,
+    server_name: &'a str,
+    inputs: &'a HashMap<String, String>,
+    strict: bool,
+}
+
+impl Vars<'_> {
+    fn walk(&self, value: &mut Value, path: &str) -> anyhow::Result<()> {
+        match value {
+            Value::String(s) => *s = self.expand(s, path)?,
+            Value::Array(items) => {
+                for (i, item) in items.iter_mut().enumerate() {
+                    self.walk(item, &format!("{path}[{i}]"))?;
+                }
+            }
+            Value::Object(map) => {
+                for (key, item) in map.iter_mut() {
+                    self.walk(item, &format!("{path}.{key}"))?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn expand(&self, input: &str, path: &str) -> anyhow::Result<String> {
+        let mut output = String::with_capacity(input.len());
+        let mut rest = input;
+        while let Some(start) = rest.find("${") {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            let escaped = rest[..start].ends_with('$');
+            output.push_str(&rest[..start - escaped as usize]);
+            let placeholder = &rest[start..start + len + 1];
+            rest = &rest[start + len + 1..];
+
+            if escaped || placeholder == SESSION_DIR {
+                output.push_str(placeholder);
+                continue;
+            }
+            let expr = &placeholder[2..placeholder.len() - 1];
+            let (name, default) = match expr.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (expr, None),
+            };
+            match (self.lookup(name), default) {
+                (Some(value), _) => output.push_str(&value),
+                (None, Some(default)) => output.push_str(default),
+                // An input is a secret the server can't do without, so never leave it empty.
+                (None, None) if name.starts_with("input:") => bail!(
+                    "{path}: input {} has no value in the secrets file, environment or its default",
+                    &name["input:".len()..]
+                ),
+                (None, None) if self.strict => bail!("{path}: undefined variable {name}"),
+                (None, None) => tracing::warn!("{path}: undefined variable {name}"),
+            }
+        }
+        output.push_str(rest);
+        Ok(output)
+    }
+
+    fn lookup(&self, name: &str) -> Option<String> {
+        match name {
+            "CONFIG_DIR" => Some(self.config_dir.to_string()),
+            "SERVER_NAME" => Some(self.server_name.to_string()),
+            "workspaceFolder" => Some(self.workspace.to_string()),
+            "userHome" => std::env::home_dir().map(|p| p.to_string_lossy().into_owned()),
+            _ => match name.split_once(':') {
+                Some(("input", id)) => self.inputs.get(id).cloned(),
+                Some(("env", name)) => std::env::var(name).ok(),
+                _ => std::env::var(name).ok(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_interpolate() {
+        let mut config = json!({
+            "mcpServers": {
+                "fetch": {
+                    "command": "${CONFIG_DIR}/bin/${SERVER_NAME}",
+                    "args": ["--port=${MCPGATE_TEST_UNSET:-8080}", "$${HOME}", "${SESSION_DIR}"],
+                    "env": { "TOKEN": "${input:token}" },
+                    "description": "${SERVER_NAME}"
+                }
+            }
+        });
+        let inputs = HashMap::from([("token".to_string(), "s3cret".to_string())]);
+        super::config(&mut config, Path::new("/etc/mcpgate"), &inputs, false).unwrap();
+
+        let fetch = &config["mcpServers"]["fetch"];
+        assert_eq!(fetch["command"], "/etc/mcpgate/bin/fetch");
+        assert_eq!(
+            fetch["args"],
+            json!(["--port=8080", "${HOME}", "${SESSION_DIR}"])
+        );
+        assert_eq!(fetch["env"]["TOKEN"], "s3cret");
+        assert_eq!(fetch["description"], "${SERVER_NAME}");
+    }
+
+    #[test]
+    fn test_interpolate_strict() {
+        let mut config = json!({
+            "mcpServers": {
+                "fetch": { "url": "http://${MCPGATE_TEST_UNSET}/mcp" }
+            }
+        });
+        let err = super::config(&mut config, Path::new("."), &HashMap::new(), true).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "mcpServers.fetch.url: undefined variable MCPGATE_TEST_UNSET"
+        );
+    }
+
+    #[test]
+    fn test_interpolate_missing_input() {
+        let mut config = json!({
+            "mcpServers": {
+                "fetch": { "url": "http://localhost/mcp", "headers": { "Authorization": "${input:token}" } }
+            }
+        });
+        let err = super::config(&mut config, Path::new("."), &HashMap::new(), false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "mcpServers.fetch.headers.Authorization: input token has no value in the secrets file, environment or its default"
+        );
+    }
+}
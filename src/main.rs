@@ -3,7 +3,7 @@ use std::{
     convert::Infallible,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     path::PathBuf,
-    sync::Arc,
+    sync::{Arc, OnceLock},
     time::Duration,
 };
 
@@ -21,8 +21,10 @@ use rmcp::transport::{
     sse_server::SseServerConfig,
     streamable_http_server::axum::{StreamableHttpServer, StreamableHttpServerConfig},
 };
+use rmcp::{RoleServer, ServiceExt, transport::IntoTransport};
 
-use clap::Parser;
+use arc_swap::ArcSwap;
+use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 use tokio::{sync::RwLock, time::sleep};
 use tokio_util::sync::CancellationToken;
@@ -31,14 +33,29 @@ use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer, Expos
 use tracing::Instrument;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod cache;
+mod clients;
 mod config;
+mod env;
 mod error;
+mod export;
+mod forwarded;
 mod gate;
-mod orphan;
-mod serde;
-use config::{Config, McpServerConfig};
+mod interpolate;
+mod preflight;
+mod process;
+mod protocol;
+mod sandbox;
+mod server;
+mod subscription;
+mod upstream;
+mod validate;
+mod workdir;
+use config::Config;
+use forwarded::{BaseUrl, TrustedProxy};
 use gate::Gate;
-use orphan::*;
+use preflight::Preflight;
+use server::McpServer;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -52,22 +69,128 @@ struct Args {
     port: u16,
 
     /// Set the configuration file to use. Default is ./config.json.
-    #[arg(short = 'C', long, default_value = None)]
+    #[arg(short = 'C', long, global = true, default_value = None)]
     conf: Option<PathBuf>,
 
     /// Enable Server-Sent Events. Default is false.
     #[arg(long)]
     sse: bool,
+
+    /// Path every route is served under, e.g. `/gateway`. Default is `/`.
+    #[arg(long, default_value = "/", value_parser = parse_base_path)]
+    base_path: String,
+
+    /// URL clients reach the base path at, e.g. `https://example.com/gateway`, used in the
+    /// discovery output and the SSE message path.
+    #[arg(long, default_value = None)]
+    public_url: Option<BaseUrl>,
+
+    /// Address or range of a proxy whose `Forwarded` and `X-Forwarded-*` headers are
+    /// trusted by discovery, e.g. `10.0.0.0/8`. Can be repeated. Needs `--public-url`
+    /// with `--sse`.
+    #[arg(long = "trusted-proxy")]
+    trusted_proxies: Vec<TrustedProxy>,
+
+    /// Run the preflight check of every server at startup, and of the servers a reload
+    /// changes. Default is false.
+    #[arg(long)]
+    preflight: bool,
+
+    /// Dotenv file of values for the `inputs` of a VS Code config, keyed by input id.
+    #[arg(long, global = true, default_value = None)]
+    secrets: Option<PathBuf>,
+
+    /// Seconds to wait for a server during preflight. Default is 30.
+    #[arg(long, global = true, default_value_t = 30)]
+    preflight_timeout: u64,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Verify every server can initialize and list its tools, then exit.
+    Check,
+    /// Print the JSON Schema of the config file, then exit.
+    #[cfg(feature = "schemars")]
+    Schema,
+}
+
+/// Namespace of the gateway's own routes, which no server may be named after.
+const DISCOVERY: &str = "_mcpgate";
+
+/// Normalizes a base path to `/path` without the trailing `/`, or empty at the root.
+fn parse_base_path(path: &str) -> Result<String, String> {
+    let path = path.trim_matches('/');
+    if path.contains(['?', '#']) {
+        return Err(format!("invalid base path {path}"));
+    }
+    Ok(if path.is_empty() {
+        String::new()
+    } else {
+        format!("/{path}")
+    })
+}
+
 struct App {
     conf_path: PathBuf,
+    secrets: Option<PathBuf>,
     bind_address: SocketAddr,
+    base_path: String,
+    public_url: Option<BaseUrl>,
+    trusted_proxies: Vec<TrustedProxy>,
     sse: bool,
-    config: Arc<RwLock<Arc<Config>>>,
-    routers: Arc<RwLock<HashMap<Arc<str>, Router>>>,
+    snapshot: Arc<ArcSwap<Snapshot>>,
+    preflight_timeout: Duration,
+    /// Whether servers are checked at startup and when a reload changes them.
+    preflight_enabled: bool,
+    preflight: Arc<RwLock<HashMap<Arc<str>, Preflight>>>,
     ct: CancellationToken,
 }
 
+#[derive(Clone)]
+struct McpRouter {
+    router: Router,
+    server: Arc<McpServer>,
+}
+
+/// The config and the routers of its servers, replaced as a whole on reload so that
+/// requests read it without locking.
+struct Snapshot {
+    config: Arc<Config>,
+    /// Built on first use, once per server.
+    routers: HashMap<Arc<str>, Arc<OnceLock<McpRouter>>>,
+}
+
+impl Snapshot {
+    /// Keeps the routers of the servers whose config is unchanged since `previous`.
+    fn new(config: Config, previous: Option<&Snapshot>) -> Self {
+        let routers = config
+            .servers
+            .iter()
+            .map(|(name, server)| {
+                let router = previous
+                    .filter(|p| p.config.servers.get(name) == Some(server))
+                    .and_then(|p| p.routers.get(name).cloned())
+                    .unwrap_or_default();
+                (name.clone(), router)
+            })
+            .collect();
+        Self {
+            config: Arc::new(config),
+            routers,
+        }
+    }
+
+    /// Routers built so far.
+    fn running(&self) -> impl Iterator<Item = (&Arc<str>, &McpRouter)> {
+        self.routers
+            .iter()
+            .filter_map(|(name, router)| Some((name, router.get()?)))
+    }
+}
+
 #[tokio::main(flavor = "multi_thread", worker_threads = 4)]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
@@ -79,9 +202,42 @@ async fn main() -> anyhow::Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    #[cfg(feature = "schemars")]
+    if let Some(Command::Schema) = args.command {
+        println!("{}", serde_json::to_string_pretty(&Config::schema())?);
+        return Ok(());
+    }
+
     let conf_path = args.conf.unwrap_or(PathBuf::from("./config.json"));
 
-    let config: Arc<Config> = Arc::new(Config::read(&conf_path)?);
+    let config = Config::read(&conf_path, args.secrets.as_deref())?;
+    let preflight_timeout = Duration::from_secs(args.preflight_timeout);
+
+    if let Some(Command::Check) = args.command {
+        let mut failed = false;
+        for (name, preflight) in preflight::check_all(&config, preflight_timeout).await {
+            failed |= !preflight.ok;
+            let detail = match (&preflight.error, &preflight.server, preflight.tools) {
+                (Some(error), _, _) => error.clone(),
+                (None, server, tools) => format!(
+                    "{}, {} tools",
+                    server.as_deref().unwrap_or("unknown server"),
+                    tools.unwrap_or_default()
+                ),
+            };
+            let status = if preflight.ok { "ok" } else { "failed" };
+            println!("{status:<6} {name} ({} ms): {detail}", preflight.elapsed_ms);
+        }
+        if failed {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // The SSE message path is fixed per server, so it can't follow `X-Forwarded-Prefix`.
+    if args.sse && !args.trusted_proxies.is_empty() && args.public_url.is_none() {
+        anyhow::bail!("--trusted-proxy with --sse needs --public-url for the SSE message path");
+    }
 
     let bind_address =
         SocketAddr::new(args.host.unwrap_or(Ipv4Addr::UNSPECIFIED.into()), args.port);
@@ -90,13 +246,24 @@ async fn main() -> anyhow::Result<()> {
 
     let app = Arc::new(App {
         conf_path: conf_path.clone(),
+        secrets: args.secrets,
         sse: args.sse,
         bind_address,
-        config: Arc::new(RwLock::new(config.clone())),
-        routers: Default::default(),
+        base_path: args.base_path,
+        public_url: args.public_url,
+        trusted_proxies: args.trusted_proxies,
+        snapshot: Arc::new(ArcSwap::from_pointee(Snapshot::new(config, None))),
+        preflight_timeout,
+        preflight_enabled: args.preflight,
+        preflight: Default::default(),
         ct: ct.clone(),
     });
 
+    if app.preflight_enabled {
+        app.run_preflight(app.snapshot.load().config.servers.keys().cloned().collect());
+    }
+    app.prewarm();
+
     let (tx, mut rx) = tokio::sync::mpsc::channel(1);
 
     let mut watcher = RecommendedWatcher::new(
@@ -107,7 +274,12 @@ async fn main() -> anyhow::Result<()> {
             .with_poll_interval(Duration::from_secs(2))
             .with_compare_contents(true),
     )?;
-    watcher.watch(conf_path.as_ref(), RecursiveMode::Recursive)?;
+    let mut watched = Vec::new();
+    follow(
+        &mut watcher,
+        &mut watched,
+        app.snapshot.load().config.watch_paths(),
+    );
 
     {
         let app = app.clone();
@@ -121,6 +293,8 @@ async fn main() -> anyhow::Result<()> {
                             _ = &mut wait => {
                                 tracing::info!("config changed, reloading... {i}");
                                 let _ = app.reload_config().await;
+                                let paths = app.snapshot.load().config.watch_paths();
+                                follow(&mut watcher, &mut watched, paths);
                                 tracing::info!("config changed, reloaded {i}");
                                 continue
                             },
@@ -141,7 +315,12 @@ async fn main() -> anyhow::Result<()> {
                     continue;
                 };
 
-                if matches!(evt.kind, EventKind::Modify(ModifyKind::Data(_))) {
+                if matches!(
+                    evt.kind,
+                    EventKind::Modify(ModifyKind::Data(_))
+                        | EventKind::Create(_)
+                        | EventKind::Remove(_)
+                ) {
                     reload = Some(Box::pin(sleep(Duration::from_secs(2))))
                 }
             }
@@ -153,10 +332,26 @@ async fn main() -> anyhow::Result<()> {
     let router = Router::new()
         .route("/{service_name}", routing::any(serve_mcp))
         .route("/{service_name}/{*x}", routing::any(serve_mcp))
-        .route("/mcp/config.json", routing::get(list_servers))
-        .route("/mcp/config", routing::get(list_servers));
+        .route(
+            &format!("/{DISCOVERY}/config.json"),
+            routing::get(list_servers),
+        )
+        .route(&format!("/{DISCOVERY}/config"), routing::get(list_servers))
+        .route(&format!("/{DISCOVERY}/metrics"), routing::get(metrics))
+        .route(&format!("/{DISCOVERY}/status"), routing::get(status));
+    #[cfg(feature = "schemars")]
+    let router = router.route(
+        &format!("/{DISCOVERY}/config.schema.json"),
+        routing::get(config_schema),
+    );
 
+    let base_path = app.base_path.clone();
     let router = router.with_state(app);
+    let router = if base_path.is_empty() {
+        router
+    } else {
+        Router::new().nest(&base_path, router)
+    };
 
     let cors = CorsLayer::new()
         .allow_origin(AllowOrigin::mirror_request())
@@ -187,36 +382,117 @@ async fn main() -> anyhow::Result<()> {
 
     tokio::signal::ctrl_c().await?;
     ct.cancel();
+    process::shutdown().await;
     Ok(())
 }
 
+/// Watches the given config paths, dropping those no longer part of the config.
+fn follow(
+    watcher: &mut RecommendedWatcher,
+    watched: &mut Vec<(PathBuf, bool)>,
+    paths: Vec<(PathBuf, bool)>,
+) {
+    for (path, _) in watched.iter().filter(|p| !paths.contains(p)) {
+        let _ = watcher.unwatch(path);
+    }
+    for (path, recursive) in paths.iter().filter(|p| !watched.contains(p)) {
+        let mode = if *recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        if let Err(e) = watcher.watch(path, mode) {
+            tracing::warn!("failed to watch {}: {e}", path.display());
+        }
+    }
+    *watched = paths;
+}
+
 impl App {
     async fn reload_config(&self) -> anyhow::Result<()> {
-        let new_config = Config::read(&self.conf_path)?;
-
-        let mut removed = {
-            self.routers
-                .read()
-                .await
-                .keys()
-                .filter(|k| !new_config.servers.contains_key(*k))
-                .cloned()
-                .collect::<Vec<_>>()
-        };
+        let new_config = Config::read(&self.conf_path, self.secrets.as_deref())?;
 
-        let removed2 = self.config.read().await.servers.iter().filter(|(n, server)| {
-            matches!(new_config.servers.get(*n), Some(new_server) if *server != new_server)
-        }).map(|(n, _)| n).cloned().collect::<Vec<_>>();
+        let snapshot = self.snapshot.load_full();
+        let changed = new_config
+            .servers
+            .iter()
+            .filter(|(n, server)| snapshot.config.servers.get(*n) != Some(*server))
+            .map(|(n, _)| n.clone())
+            .collect::<Vec<_>>();
+        self.preflight
+            .write()
+            .await
+            .retain(|n, _| new_config.servers.contains_key(n));
+
+        self.snapshot
+            .store(Arc::new(Snapshot::new(new_config, Some(&snapshot))));
+        if self.preflight_enabled {
+            self.run_preflight(changed);
+        }
+        self.prewarm();
+        Ok(())
+    }
 
-        removed.extend(removed2);
+    /// Checks the given servers in the background and records the results for `/_mcpgate/status`.
+    fn run_preflight(&self, names: Vec<Arc<str>>) {
+        let snapshot = self.snapshot.clone();
+        let preflight = self.preflight.clone();
+        let timeout = self.preflight_timeout;
+        tokio::spawn(async move {
+            for name in names {
+                let Some(server) = snapshot.load().config.servers.get(&name).cloned() else {
+                    continue;
+                };
+                let res = Preflight::run(name.clone(), &server, timeout).await;
+                match res.error.as_deref() {
+                    Some(error) => tracing::warn!(server = %name, "preflight failed: {error}"),
+                    None => tracing::info!(server = %name, "preflight passed"),
+                }
+                preflight.write().await.insert(name, res);
+            }
+        });
+    }
 
-        let mut routers = self.routers.write().await;
-        for n in removed {
-            routers.remove(&n);
-        }
+    /// Returns the router of a service, building it on first use.
+    fn router(&self, name: &Arc<str>) -> Option<McpRouter> {
+        let snapshot = self.snapshot.load();
+        let config = snapshot.config.servers.get(name)?;
+        let router = snapshot.routers.get(name)?.get_or_init(|| {
+            let server = Arc::new(McpServer::new(name.clone(), config.clone()));
+            McpRouter {
+                router: make_mcp_router(
+                    name,
+                    server.clone(),
+                    self.sse,
+                    self.public_url
+                        .as_ref()
+                        .map_or(&self.base_path, |url| &url.prefix),
+                    self.bind_address,
+                    self.ct.clone(),
+                ),
+                server,
+            }
+        });
+        Some(router.clone())
+    }
 
-        *self.config.write().await = Arc::new(new_config);
-        Ok(())
+    /// Builds the services configured with `prewarm` and starts filling their pools.
+    fn prewarm(&self) {
+        let names = self
+            .snapshot
+            .load()
+            .config
+            .servers
+            .iter()
+            .filter(|(_, s)| s.options().prewarm() > 0)
+            .map(|(n, _)| n.clone())
+            .collect::<Vec<_>>();
+
+        for name in names {
+            if let Some(router) = self.router(&name) {
+                router.server.refill();
+            }
+        }
     }
 }
 
@@ -280,95 +556,192 @@ async fn list_servers(
     Query(params): Query<HashMap<String, String>>,
     State(app): State<Arc<App>>,
     req: Request,
-) -> Json<Config> {
-    let sse = params.contains_key("sse");
-    let schame = if params.contains_key("https") {
-        "https"
-    } else {
-        "http"
+) -> axum::response::Response {
+    let format = match params.get("format").map(String::as_str) {
+        None | Some("raw") => None,
+        Some(format) => match format.parse::<export::Format>() {
+            Ok(format) => Some(format),
+            Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        },
     };
-    let host = params.get("host").map(|s| s.as_str());
+    let selected = params
+        .get("servers")
+        .map(|names| names.split(',').collect::<Vec<_>>());
+    let sse = params.contains_key("sse");
 
-    let host = host.unwrap_or_else(|| {
-        req.headers()
-            .get(header::HOST)
-            .iter()
-            .flat_map(|x| x.to_str())
-            .next()
-            .unwrap_or_default()
-    });
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| c.0.ip());
+    let mut base = forwarded::base_url(
+        app.public_url.as_ref(),
+        &app.trusted_proxies,
+        peer,
+        req.headers(),
+        &app.base_path,
+    );
+    if params.contains_key("https") {
+        base.scheme = "https".to_string();
+    }
+    if let Some(host) = params.get("host") {
+        base.host = host.clone();
+    }
 
-    let servers = app
-        .config
-        .read()
-        .await
+    let config = app.snapshot.load().config.clone();
+    if let Some(selected) = &selected {
+        let unknown = selected
+            .iter()
+            .filter(|name| !config.servers.contains_key(**name))
+            .copied()
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            let message = format!("unknown servers: {}", unknown.join(", "));
+            return (StatusCode::BAD_REQUEST, message).into_response();
+        }
+    }
+    let mut servers = config
         .servers
         .iter()
+        .filter(|(name, _)| selected.as_ref().is_none_or(|s| s.contains(&&***name)))
+        .collect::<Vec<_>>();
+    servers.sort_by_key(|(name, _)| *name);
+
+    if let Some(format) = format {
+        let endpoints = servers
+            .iter()
+            .map(|(name, _)| export::Endpoint {
+                name,
+                url: if sse {
+                    base.join(&format!("/{name}/sse"))
+                } else {
+                    base.join(&format!("/{name}"))
+                },
+                sse,
+            })
+            .collect::<Vec<_>>();
+        // Clients authenticate to the gateway the way the discovery request did.
+        let headers = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| ("Authorization".to_string(), v.to_string()))
+            .into_iter()
+            .collect();
+        let snippet = export::snippet(format, &endpoints, &headers);
+        return match format {
+            export::Format::Continue => match serde_yaml::to_string(&snippet) {
+                Ok(yaml) => ([(header::CONTENT_TYPE, "application/yaml")], yaml).into_response(),
+                Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+            },
+            _ => Json(snippet).into_response(),
+        };
+    }
+
+    let servers = servers
+        .into_iter()
         .map(|(name, s)| {
             let config = if sse {
-                s.to_sse(format!("{schame}://{host}/{name}/sse"))
+                s.to_sse(base.join(&format!("/{name}/sse")))
             } else {
-                s.to_streamable(format!("{schame}://{host}/{name}"))
+                s.to_streamable(base.join(&format!("/{name}")))
             };
 
             (name.clone(), Arc::new(config))
         })
         .collect();
 
-    let config = Config { servers };
+    let config = Config {
+        servers,
+        ..Default::default()
+    };
+
+    Json(config).into_response()
+}
+
+#[derive(Serialize)]
+struct ServerStatus {
+    name: Arc<str>,
+    running: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preflight: Option<Preflight>,
+    /// Most recent stderr lines of a running stdio server.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stderr: Vec<String>,
+}
 
-    Json(config)
+#[cfg(feature = "schemars")]
+async fn config_schema() -> Json<schemars::schema::RootSchema> {
+    Json(Config::schema())
+}
+
+async fn status(State(app): State<Arc<App>>) -> Json<ListData<ServerStatus>> {
+    let snapshot = app.snapshot.load();
+    let preflight = app.preflight.read().await;
+
+    let mut servers = snapshot
+        .routers
+        .iter()
+        .map(|(name, router)| ServerStatus {
+            name: name.clone(),
+            running: router.get().is_some(),
+            preflight: preflight.get(name).cloned(),
+            stderr: router
+                .get()
+                .map(|router| router.server.stderr().tail())
+                .unwrap_or_default(),
+        })
+        .collect::<Vec<_>>();
+    servers.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Json(servers.into())
+}
+
+async fn metrics(State(app): State<Arc<App>>) -> impl IntoResponse {
+    let mut out = String::new();
+    for (name, router) in app.snapshot.load().running() {
+        router.server.cache().write_metrics(name, &mut out);
+    }
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out)
 }
 
 async fn serve_mcp(
     Path(params): Path<HashMap<String, String>>,
     State(app): State<Arc<App>>,
-    req: Request,
+    mut req: Request,
 ) -> Result<axum::http::Response<axum::body::Body>, Infallible> {
     use axum::response::IntoResponse;
+    // The router decodes the first segment, which is matched against server names as is.
     let service_name: Arc<str> = params.get("service_name").unwrap().to_string().into();
 
-    let path_prefix = {
-        let path = req.uri().path().to_string();
-        path[0..(path.find(service_name.as_ref()).unwrap_or_default() + service_name.len())]
-            .to_string()
+    let Some(router) = app.router(&service_name) else {
+        return Ok((
+            StatusCode::NOT_FOUND,
+            format!("Service {service_name} not found"),
+        )
+            .into_response());
     };
+    *req.uri_mut() = strip_service(req.uri());
+    router.router.clone().call(req).await
+}
 
-    let router = app.routers.read().await.get(&service_name).cloned();
-
-    let router = match router {
-        Some(router) => router,
-        None => {
-            let Some(config) = app.config.read().await.servers.get(&service_name).cloned() else {
-                return Ok((
-                    StatusCode::NOT_FOUND,
-                    format!("Service {service_name} not found"),
-                )
-                    .into_response());
-            };
-
-            let router = make_mcp_router(
-                &service_name,
-                config,
-                app.sse,
-                app.bind_address,
-                app.ct.clone(),
-            );
-            app.routers
-                .write()
-                .await
-                .insert(service_name, router.clone());
-            router
-        }
+/// Removes the first path segment, naming the server, leaving the path within its router.
+fn strip_service(uri: &http::Uri) -> http::Uri {
+    let path = uri.path().trim_start_matches('/');
+    let rest = path.find('/').map_or("/", |i| &path[i..]);
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{rest}?{query}"),
+        None => rest.to_string(),
     };
-    let res = Router::new().nest(&path_prefix, router).call(req).await?;
-    Ok(res)
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    http::Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
 }
 
 fn make_mcp_router(
     name: &str,
-    server_config: Arc<McpServerConfig>,
+    server: Arc<McpServer>,
     sse: bool,
+    prefix: &str,
     bind_address: SocketAddr,
     ct: CancellationToken,
 ) -> Router {
@@ -382,12 +755,18 @@ fn make_mcp_router(
                 ct: ct.clone(),
                 sse_keep_alive: None,
             },
-            format!("/{name}/message"),
+            format!("{prefix}/{name}/message"),
         );
 
-        sse_server.with_service({
-            let server_config = server_config.clone();
-            move || Gate::new(server_config.clone())
+        tokio::spawn({
+            let server = server.clone();
+            let mut sse_server = sse_server;
+            async move {
+                while let Some(transport) = sse_server.next_transport().await {
+                    let ct = sse_server.config.ct.child_token();
+                    tokio::spawn(serve_session(server.clone(), transport, ct));
+                }
+            }
         });
 
         service_router = service_router.merge(sse_router)
@@ -401,9 +780,15 @@ fn make_mcp_router(
                 ..Default::default()
             });
 
-        streamable_http_server.with_service({
-            let server_config = server_config.clone();
-            move || Gate::new(server_config.clone())
+        tokio::spawn({
+            let server = server.clone();
+            let mut streamable_http_server = streamable_http_server;
+            async move {
+                while let Some(transport) = streamable_http_server.next_transport().await {
+                    let ct = streamable_http_server.config.ct.child_token();
+                    tokio::spawn(serve_session(server.clone(), transport, ct));
+                }
+            }
         });
 
         streamable_router
@@ -413,3 +798,61 @@ fn make_mcp_router(
 
     service_router
 }
+
+/// Serves one downstream session; cancelling `ct` closes it.
+async fn serve_session<T, E, A>(server: Arc<McpServer>, transport: T, ct: CancellationToken)
+where
+    T: IntoTransport<RoleServer, E, A>,
+    E: std::error::Error + From<std::io::Error> + Send + Sync + 'static,
+{
+    match Gate::new(server, ct.clone())
+        .serve_with_ct(transport, ct)
+        .await
+    {
+        Ok(running) => {
+            if let Err(e) = running.waiting().await {
+                tracing::error!(error = %e, "session task failed");
+            }
+        }
+        Err(e) => tracing::error!(error = %e, "failed to start session"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_service() {
+        let strip = |uri: &str| strip_service(&uri.parse().unwrap()).to_string();
+        assert_eq!(strip("/fetch"), "/");
+        assert_eq!(strip("/fetch/sse"), "/sse");
+        assert_eq!(strip("/sse/message?sessionId=1"), "/message?sessionId=1");
+        assert_eq!(strip("/my%20server/sse"), "/sse");
+        assert_eq!(strip("http://gw/fetch/"), "http://gw/");
+    }
+
+    #[test]
+    fn test_snapshot_keeps_unchanged_routers() {
+        let config = |url: &str| {
+            serde_json::from_value::<Config>(serde_json::json!({
+                "mcpServers": {
+                    "fetch": { "type": "sse", "url": "http://localhost/sse" },
+                    "time": { "type": "sse", "url": url },
+                }
+            }))
+            .unwrap()
+        };
+        let previous = Snapshot::new(config("http://localhost/time"), None);
+        let snapshot = Snapshot::new(config("http://localhost/time2"), Some(&previous));
+
+        assert!(Arc::ptr_eq(
+            &snapshot.routers["fetch"],
+            &previous.routers["fetch"]
+        ));
+        assert!(!Arc::ptr_eq(
+            &snapshot.routers["time"],
+            &previous.routers["time"]
+        ));
+    }
+}
@@ -0,0 +1,100 @@
+use std::{sync::Arc, time::Duration};
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::cache::Cache;
+use crate::config::{Config, McpServerConfig};
+use crate::process::{StderrLog, StderrSink};
+use crate::upstream::Upstream;
+
+/// Outcome of starting a server, initializing it and listing its tools.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Preflight {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Stderr of a failed stdio server.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stderr: Vec<String>,
+    pub elapsed_ms: u128,
+}
+
+impl Preflight {
+    pub async fn run(name: Arc<str>, config: &McpServerConfig, timeout: Duration) -> Self {
+        let started = Instant::now();
+        let log = Arc::new(StderrLog::new(name, config.stderr()));
+        let res = tokio::time::timeout(timeout, async {
+            let upstream = Upstream::new(config.client_info(None), Arc::new(Cache::default()));
+            let stderr = StderrSink::new(log.clone(), None, upstream.exit().clone());
+            let client = config.create_client(upstream, stderr).await?;
+            let server = client
+                .peer_info()
+                .map(|info| format!("{} {}", info.server_info.name, info.server_info.version));
+            let tools = client.list_all_tools().await?.len();
+            anyhow::Ok((server, tools))
+        })
+        .await;
+        let elapsed_ms = started.elapsed().as_millis();
+
+        match res {
+            Ok(Ok((server, tools))) => Self {
+                ok: true,
+                server,
+                tools: Some(tools),
+                error: None,
+                stderr: Vec::new(),
+                elapsed_ms,
+            },
+            Ok(Err(e)) => Self::failed(e.to_string(), &log, elapsed_ms),
+            Err(_) => Self::failed(format!("timed out after {timeout:?}"), &log, elapsed_ms),
+        }
+    }
+
+    fn failed(error: String, log: &StderrLog, elapsed_ms: u128) -> Self {
+        Self {
+            ok: false,
+            server: None,
+            tools: None,
+            error: Some(error),
+            stderr: log.tail(),
+            elapsed_ms,
+        }
+    }
+}
+
+/// Runs the preflight of every configured server concurrently, sorted by name.
+pub async fn check_all(config: &Config, timeout: Duration) -> Vec<(Arc<str>, Preflight)> {
+    let tasks = config
+        .servers
+        .iter()
+        .map(|(name, server)| {
+            let task = {
+                let name = name.clone();
+                let server = server.clone();
+                tokio::spawn(async move { Preflight::run(name, &server, timeout).await })
+            };
+            (name.clone(), task)
+        })
+        .collect::<Vec<_>>();
+
+    let mut results = Vec::with_capacity(tasks.len());
+    for (name, task) in tasks {
+        let preflight = task.await.unwrap_or_else(|e| Preflight {
+            ok: false,
+            server: None,
+            tools: None,
+            error: Some(format!("preflight did not complete: {e}")),
+            stderr: Vec::new(),
+            elapsed_ms: 0,
+        });
+        results.push((name, preflight));
+    }
+    results.sort_by(|(a, _), (b, _)| a.cmp(b));
+    results
+}
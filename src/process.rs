@@ -0,0 +1,469 @@
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    process::{ExitStatus, Stdio},
+    sync::{Arc, LazyLock, Mutex, OnceLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, ReadBuf},
+    process::{Child, ChildStderr, ChildStdin, ChildStdout, Command},
+    sync::watch,
+};
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+use crate::config::{LogLevel, SandboxConfig, StderrConfig};
+use crate::sandbox::{self, Cgroup};
+use crate::workdir::SessionDir;
+
+/// Stderr of one configured server, logged line by line with the most recent lines kept.
+#[derive(Debug)]
+pub struct StderrLog {
+    server: Arc<str>,
+    config: StderrConfig,
+    lines: Mutex<VecDeque<String>>,
+}
+
+impl StderrLog {
+    pub fn new(server: Arc<str>, config: StderrConfig) -> Self {
+        Self {
+            server,
+            config,
+            lines: Default::default(),
+        }
+    }
+
+    /// Returns the most recent lines, oldest first.
+    pub fn tail(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn push(&self, session: Option<u64>, line: String) {
+        let server = &*self.server;
+        match self.config.level_of(&line) {
+            LogLevel::Error => tracing::error!(server, session, "{line}"),
+            LogLevel::Warn => tracing::warn!(server, session, "{line}"),
+            LogLevel::Info => tracing::info!(server, session, "{line}"),
+            LogLevel::Debug => tracing::debug!(server, session, "{line}"),
+            LogLevel::Trace => tracing::trace!(server, session, "{line}"),
+        }
+
+        let capacity = self.config.lines();
+        if capacity == 0 {
+            return;
+        }
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() >= capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+}
+
+/// Where a stdio server reports to: its stderr goes to the server log, tagged with the
+/// session the process was started for, and the reason it died unexpectedly to `exit`.
+#[derive(Debug, Clone)]
+pub struct StderrSink {
+    log: Arc<StderrLog>,
+    session: Option<u64>,
+    exit: Arc<OnceLock<String>>,
+}
+
+impl StderrSink {
+    pub fn new(log: Arc<StderrLog>, session: Option<u64>, exit: Arc<OnceLock<String>>) -> Self {
+        Self { log, session, exit }
+    }
+
+    fn capture(self, stderr: ChildStderr) {
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(stderr);
+            let mut buf = Vec::new();
+            loop {
+                buf.clear();
+                match reader.read_until(b'\n', &mut buf).await {
+                    Ok(0) => break,
+                    Ok(_) => {
+                        let line = String::from_utf8_lossy(&buf);
+                        let line = line.trim_end_matches(['\r', '\n']);
+                        self.log.push(self.session, line.to_string());
+                    }
+                    Err(e) => {
+                        tracing::debug!(server = &*self.log.server, "stderr closed: {e}");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Stdio servers that are still running, stopped together when the gateway shuts down.
+struct Supervisors {
+    ct: CancellationToken,
+    running: watch::Sender<usize>,
+}
+
+static SUPERVISORS: LazyLock<Supervisors> = LazyLock::new(|| Supervisors {
+    ct: CancellationToken::new(),
+    running: watch::Sender::new(0),
+});
+
+/// Stops every stdio server and waits until all of them have been reaped.
+pub async fn shutdown() {
+    SUPERVISORS.ct.cancel();
+    let _ = SUPERVISORS.running.subscribe().wait_for(|n| *n == 0).await;
+}
+
+/// A spawned stdio server. Reading from it reads the server's stdout, and dropping it
+/// stops the server.
+#[derive(Debug)]
+pub struct StdioProcess {
+    stdout: ChildStdout,
+    _stop: DropGuard,
+}
+
+/// Stdin of a stdio server; its supervisor closes it before signalling the server.
+#[derive(Debug)]
+pub struct StdioInput(Arc<Mutex<Option<ChildStdin>>>);
+
+impl StdioProcess {
+    /// Spawns the command in its own process group with piped stdio.
+    ///
+    /// The server is stopped once the returned process is dropped or the gateway shuts
+    /// down: its stdin is closed, the group is sent SIGTERM if the server hasn't exited
+    /// on EOF shortly after, and SIGKILL once `grace` has passed.
+    pub fn spawn(
+        mut cmd: Command,
+        stderr: StderrSink,
+        grace: Duration,
+        sandbox: &SandboxConfig,
+        session_dir: Option<SessionDir>,
+    ) -> io::Result<(Self, StdioInput)> {
+        cmd.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        #[cfg(unix)]
+        cmd.process_group(0);
+        let cgroup = sandbox::apply(sandbox, &mut cmd, &stderr.log.server)?;
+        let mut child = cmd.spawn().map_err(|e| {
+            let program = cmd.as_std().get_program().to_string_lossy();
+            io::Error::new(e.kind(), format!("failed to start {program}: {e}"))
+        })?;
+
+        if let Some(err) = child.stderr.take() {
+            stderr.clone().capture(err);
+        }
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(io::Error::other("child stdio is not piped"));
+        };
+
+        let stdin = Arc::new(Mutex::new(Some(stdin)));
+        let stop = SUPERVISORS.ct.child_token();
+        SUPERVISORS.running.send_modify(|n| *n += 1);
+        tokio::spawn(supervise(
+            child,
+            stdin.clone(),
+            stop.clone(),
+            grace,
+            cgroup,
+            session_dir,
+            stderr,
+        ));
+
+        Ok((
+            Self {
+                stdout,
+                _stop: stop.drop_guard(),
+            },
+            StdioInput(stdin),
+        ))
+    }
+}
+
+async fn supervise(
+    mut child: Child,
+    stdin: Arc<Mutex<Option<ChildStdin>>>,
+    stop: CancellationToken,
+    grace: Duration,
+    cgroup: Option<Cgroup>,
+    session_dir: Option<SessionDir>,
+    stderr: StderrSink,
+) {
+    let server = &*stderr.log.server;
+    let session = stderr.session;
+
+    let status = tokio::select! {
+        status = child.wait() => {
+            if let Ok(status) = status {
+                let _ = stderr.exit.set(describe_exit(status, cgroup.as_ref()));
+            }
+            status
+        }
+        _ = stop.cancelled() => {
+            stdin.lock().unwrap().take();
+            let eof = grace / 4;
+            if !leader_exited(&mut child, eof).await {
+                signal(&mut child, Signal::Term);
+                if !leader_exited(&mut child, grace.saturating_sub(eof)).await {
+                    tracing::warn!(server, session, "stdio server did not stop in {grace:?}, killing it");
+                }
+            }
+            // Take down whatever the server left behind in its group, e.g. `podman run`.
+            signal(&mut child, Signal::Kill);
+            child.wait().await
+        }
+    };
+    // Once the leader is reaped its pgid may be reused, so what a server that exited on
+    // its own left behind can only be found through its cgroup.
+    if let Some(cgroup) = cgroup {
+        cgroup.kill();
+        cgroup.drain().await;
+    }
+    drop(session_dir);
+
+    match status {
+        Ok(status) => tracing::info!(server, session, "stdio server exited with {status}"),
+        Err(e) => tracing::error!(server, session, "failed to wait for stdio server: {e}"),
+    }
+    SUPERVISORS.running.send_modify(|n| *n -= 1);
+}
+
+fn describe_exit(status: ExitStatus, cgroup: Option<&Cgroup>) -> String {
+    let mut reason = format!("stdio server exited with {status}");
+    if cgroup.is_some_and(Cgroup::oom_killed) {
+        reason.push_str(" (memory limit exceeded)");
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if status.signal() == Some(libc::SIGXCPU) {
+            reason.push_str(" (CPU time limit exceeded)");
+        }
+    }
+    reason
+}
+
+enum Signal {
+    Term,
+    Kill,
+}
+
+/// Signals the process group of the child, unless the leader has already been reaped.
+#[cfg(unix)]
+fn signal(child: &mut Child, signal: Signal) {
+    let Some(pid) = child.id() else {
+        return;
+    };
+    let signal = match signal {
+        Signal::Term => libc::SIGTERM,
+        Signal::Kill => libc::SIGKILL,
+    };
+    // SAFETY: the child was spawned as the leader of its own process group, and is not
+    // reaped yet, so the pgid still belongs to it.
+    unsafe {
+        libc::killpg(pid as libc::pid_t, signal);
+    }
+}
+
+#[cfg(not(unix))]
+fn signal(child: &mut Child, _signal: Signal) {
+    let _ = child.start_kill();
+}
+
+/// Waits up to `timeout` for the group leader to exit, leaving it unreaped.
+#[cfg(target_os = "linux")]
+async fn leader_exited(child: &mut Child, timeout: Duration) -> bool {
+    let Some(pid) = child.id() else {
+        return true;
+    };
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        // SAFETY: `info` is a plain C struct; WNOWAIT leaves the child to be reaped by tokio.
+        let exited = unsafe {
+            let mut info: libc::siginfo_t = std::mem::zeroed();
+            let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+            libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, flags) < 0 || info.si_pid() != 0
+        };
+        if exited {
+            return true;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Waits up to `timeout` for the child to exit, reaping it, after which its group is no
+/// longer signalled.
+#[cfg(not(target_os = "linux"))]
+async fn leader_exited(child: &mut Child, timeout: Duration) -> bool {
+    tokio::time::timeout(timeout, child.wait()).await.is_ok()
+}
+
+impl AsyncRead for StdioProcess {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for StdioInput {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(stdin) => Pin::new(stdin).poll_write(cx, buf),
+            None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(stdin) => Pin::new(stdin).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(stdin) => Pin::new(stdin).poll_shutdown(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stderr_tail() {
+        let config = serde_json::from_str::<StderrConfig>(r#"{ "lines": 2 }"#).unwrap();
+        let log = Arc::new(StderrLog::new("sh".into(), config));
+
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo one >&2; echo two >&2; echo three >&2"]);
+        let sink = StderrSink::new(log.clone(), None, Default::default());
+        let (_process, _stdin) =
+            StdioProcess::spawn(cmd, sink, Duration::from_secs(1), &Default::default(), None)
+                .unwrap();
+
+        for _ in 0..50 {
+            if log.tail().last().is_some_and(|l| l == "three") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(log.tail(), ["two", "three"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_stop_kills_process_group() {
+        let log = Arc::new(StderrLog::new("sh".into(), Default::default()));
+
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "trap '' TERM; sleep 30 & echo $! >&2; wait"]);
+        let sink = StderrSink::new(log.clone(), None, Default::default());
+        let process = StdioProcess::spawn(
+            cmd,
+            sink,
+            Duration::from_millis(100),
+            &Default::default(),
+            None,
+        )
+        .unwrap();
+
+        let mut pid = None;
+        for _ in 0..50 {
+            pid = log
+                .tail()
+                .first()
+                .and_then(|l| l.parse::<libc::pid_t>().ok());
+            if pid.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let pid = pid.unwrap();
+        drop(process);
+
+        // Orphans are reaped by init, so a zombie counts as stopped.
+        let alive = || {
+            std::fs::read_to_string(format!("/proc/{pid}/stat"))
+                .is_ok_and(|stat| !stat.contains(") Z "))
+        };
+        for _ in 0..50 {
+            if !alive() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!alive());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_cpu_time_exceeded() {
+        let log = Arc::new(StderrLog::new("sh".into(), Default::default()));
+        let sandbox = serde_json::from_str::<SandboxConfig>(r#"{ "cpuTime": 1 }"#).unwrap();
+
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "while :; do :; done"]);
+        let exit = Arc::new(OnceLock::new());
+        let sink = StderrSink::new(log, None, exit.clone());
+        let (_process, _stdin) =
+            StdioProcess::spawn(cmd, sink, Duration::from_secs(1), &sandbox, None).unwrap();
+
+        for _ in 0..250 {
+            if exit.get().is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let exit = exit.get().unwrap();
+        assert!(exit.ends_with("(CPU time limit exceeded)"), "{exit}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_stop_waits_for_eof() {
+        let log = Arc::new(StderrLog::new("sh".into(), Default::default()));
+
+        let mut cmd = Command::new("sh");
+        cmd.args([
+            "-c",
+            "trap 'echo term >&2; exit 1' TERM; echo ready >&2; while read -r line; do :; done; echo eof >&2",
+        ]);
+        let sink = StderrSink::new(log.clone(), None, Default::default());
+        let (process, _stdin) =
+            StdioProcess::spawn(cmd, sink, Duration::from_secs(2), &Default::default(), None)
+                .unwrap();
+
+        for _ in 0..50 {
+            if log.tail().first().is_some_and(|l| l == "ready") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        drop(process);
+
+        for _ in 0..50 {
+            if log.tail().len() > 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(log.tail(), ["ready", "eof"]);
+    }
+}
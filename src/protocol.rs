@@ -0,0 +1,142 @@
+use rmcp::model::{CallToolResult, ProtocolVersion, RawContent, ServerInfo, Tool};
+
+/// Protocol revisions the gateway knows how to bridge, oldest first.
+///
+/// 2025-06-18 is not among them: the rmcp model the gateway is built on has no
+/// `structuredContent`, `outputSchema`, resource links or elicitation, so those could
+/// not be forwarded. Clients asking for it are answered with the newest revision here.
+const KNOWN_VERSIONS: &[ProtocolVersion] =
+    &[ProtocolVersion::V_2024_11_05, ProtocolVersion::V_2025_03_26];
+
+/// The protocol revisions negotiated with the downstream client and the upstream server.
+///
+/// The gateway always asks the upstream for the latest revision it knows, and answers the
+/// downstream with the revision it requested whenever that one is older. Results coming
+/// back from the upstream are then down-converted to what the downstream understands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolBridge {
+    downstream: ProtocolVersion,
+    upstream: ProtocolVersion,
+}
+
+impl Default for ProtocolBridge {
+    fn default() -> Self {
+        Self {
+            downstream: ProtocolVersion::LATEST,
+            upstream: ProtocolVersion::LATEST,
+        }
+    }
+}
+
+impl ProtocolBridge {
+    pub fn negotiate(requested: ProtocolVersion, upstream: ProtocolVersion) -> Self {
+        let downstream = if KNOWN_VERSIONS.contains(&requested) && requested < upstream {
+            requested
+        } else {
+            upstream.clone()
+        };
+        Self {
+            downstream,
+            upstream,
+        }
+    }
+
+    pub fn downstream(&self) -> &ProtocolVersion {
+        &self.downstream
+    }
+
+    pub fn upstream(&self) -> &ProtocolVersion {
+        &self.upstream
+    }
+
+    /// Whether the downstream speaks 2024-11-05 while the upstream speaks something newer.
+    fn is_legacy(&self) -> bool {
+        self.downstream == ProtocolVersion::V_2024_11_05 && self.upstream > self.downstream
+    }
+
+    pub fn server_info(&self, mut info: ServerInfo) -> ServerInfo {
+        info.protocol_version = self.downstream.clone();
+        if self.is_legacy() {
+            info.capabilities.completions = None;
+        }
+        info
+    }
+
+    pub fn tools(&self, mut tools: Vec<Tool>) -> Vec<Tool> {
+        if self.is_legacy() {
+            for tool in tools.iter_mut() {
+                tool.annotations = None;
+            }
+        }
+        tools
+    }
+
+    pub fn call_tool_result(&self, mut result: CallToolResult) -> CallToolResult {
+        if self.is_legacy() {
+            for content in result.content.iter_mut() {
+                if let RawContent::Audio(audio) = &content.raw {
+                    content.raw = RawContent::text(format!(
+                        "[{} audio omitted: requires MCP {}]",
+                        audio.mime_type, self.upstream
+                    ));
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rmcp::model::{AnnotateAble, Content, RawAudioContent, ServerCapabilities};
+
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let bridge =
+            ProtocolBridge::negotiate(ProtocolVersion::V_2024_11_05, ProtocolVersion::V_2025_03_26);
+        assert_eq!(bridge.downstream(), &ProtocolVersion::V_2024_11_05);
+        assert!(bridge.is_legacy());
+
+        let bridge =
+            ProtocolBridge::negotiate(ProtocolVersion::V_2025_03_26, ProtocolVersion::V_2024_11_05);
+        assert_eq!(bridge.downstream(), &ProtocolVersion::V_2024_11_05);
+        assert!(!bridge.is_legacy());
+    }
+
+    #[test]
+    fn test_negotiate_unknown_revision() {
+        let requested: ProtocolVersion = serde_json::from_str(r#""2025-06-18""#).unwrap();
+        let bridge = ProtocolBridge::negotiate(requested, ProtocolVersion::V_2025_03_26);
+        assert_eq!(bridge.downstream(), &ProtocolVersion::V_2025_03_26);
+        assert!(!bridge.is_legacy());
+    }
+
+    #[test]
+    fn test_legacy_down_conversion() {
+        let bridge =
+            ProtocolBridge::negotiate(ProtocolVersion::V_2024_11_05, ProtocolVersion::V_2025_03_26);
+
+        let info = bridge.server_info(ServerInfo {
+            capabilities: ServerCapabilities::builder().enable_completions().build(),
+            ..Default::default()
+        });
+        assert_eq!(info.protocol_version, ProtocolVersion::V_2024_11_05);
+        assert_eq!(info.capabilities.completions, None);
+
+        let result = bridge.call_tool_result(CallToolResult::success(vec![Content::new(
+            RawContent::Audio(
+                RawAudioContent {
+                    data: String::new(),
+                    mime_type: "audio/wav".to_string(),
+                }
+                .no_annotation(),
+            ),
+            None,
+        )]));
+        assert!(
+            matches!(&result.content[0].raw, RawContent::Text(t) if t.text.contains("audio/wav"))
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    io,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::process::Command;
+
+use crate::config::{CgroupConfig, SandboxConfig};
+
+static NEXT_CGROUP: AtomicU64 = AtomicU64::new(0);
+
+/// Cgroup of one server process, removed once the process has exited.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    fn create(config: &CgroupConfig, server: &str) -> io::Result<Self> {
+        let name = server
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        let path = config.parent().join(format!(
+            "{name}.{}.{}",
+            std::process::id(),
+            NEXT_CGROUP.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir(&path).map_err(|e| cgroup_err(&path, e))?;
+        let cgroup = Self { path };
+
+        if let Some(memory_max) = config.memory_max() {
+            cgroup.write("memory.max", &memory_max.to_string())?;
+        }
+        if let Some(cpu_percent) = config.cpu_percent() {
+            cgroup.write(
+                "cpu.max",
+                &format!("{} 100000", cpu_percent.get() as u64 * 1000),
+            )?;
+        }
+        Ok(cgroup)
+    }
+
+    fn write(&self, file: &str, value: &str) -> io::Result<()> {
+        let path = self.path.join(file);
+        std::fs::write(&path, value).map_err(|e| cgroup_err(&path, e))
+    }
+
+    /// Waits a moment for the processes left in the cgroup to be reaped, so that it
+    /// can be removed.
+    pub async fn drain(&self) {
+        for _ in 0..20 {
+            match std::fs::read_to_string(self.path.join("cgroup.procs")) {
+                Ok(procs) if !procs.trim().is_empty() => {
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Kills every process left in the cgroup.
+    pub fn kill(&self) {
+        if self.write("cgroup.kill", "1").is_ok() {
+            return;
+        }
+        // `cgroup.kill` only exists since Linux 5.14.
+        let Ok(procs) = std::fs::read_to_string(self.path.join("cgroup.procs")) else {
+            return;
+        };
+        #[cfg(unix)]
+        for pid in procs
+            .lines()
+            .filter_map(|l| l.trim().parse::<libc::pid_t>().ok())
+        {
+            // SAFETY: only sends a signal to a process listed in the cgroup.
+            unsafe {
+                libc::kill(pid, libc::SIGKILL);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = procs;
+    }
+
+    /// Whether the kernel killed a process of this cgroup for exceeding `memory.max`.
+    pub fn oom_killed(&self) -> bool {
+        std::fs::read_to_string(self.path.join("memory.events"))
+            .ok()
+            .and_then(|events| {
+                events
+                    .lines()
+                    .find_map(|l| l.strip_prefix("oom_kill "))
+                    .and_then(|n| n.trim().parse::<u64>().ok())
+            })
+            .is_some_and(|n| n > 0)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir(&self.path) {
+            tracing::warn!("failed to remove cgroup {}: {e}", self.path.display());
+        }
+    }
+}
+
+fn cgroup_err(path: &std::path::Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("cgroup {}: {e}", path.display()))
+}
+
+/// Applies the sandbox to the command, returning the cgroup it will start in, if any.
+#[cfg(unix)]
+pub fn apply(
+    config: &SandboxConfig,
+    cmd: &mut Command,
+    server: &str,
+) -> io::Result<Option<Cgroup>> {
+    use std::os::fd::AsRawFd;
+
+    if let Some(uid) = config.uid() {
+        cmd.uid(uid);
+    }
+    if let Some(gid) = config.gid() {
+        cmd.gid(gid);
+    }
+
+    let cgroup = config
+        .cgroup()
+        .map(|c| Cgroup::create(c, server))
+        .transpose()?;
+    let procs = cgroup
+        .as_ref()
+        .map(|c| {
+            let path = c.path.join("cgroup.procs");
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .map_err(|e| cgroup_err(&path, e))
+        })
+        .transpose()?;
+
+    // The hard CPU limit is a second above the soft one, so the server is sent SIGXCPU,
+    // which is reported as exceeding the limit, instead of being killed outright.
+    let limits = [
+        (libc::RLIMIT_AS, config.memory().map(|m| (m, m))),
+        (
+            libc::RLIMIT_CPU,
+            config.cpu_time().map(|t| (t, t.saturating_add(1))),
+        ),
+        (libc::RLIMIT_NOFILE, config.open_files().map(|n| (n, n))),
+    ];
+    let no_new_privs = config.no_new_privs();
+
+    // SAFETY: the closure only makes async-signal-safe system calls.
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(procs) = procs.as_ref()
+                && libc::write(procs.as_raw_fd(), b"0".as_ptr().cast(), 1) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            for (resource, limit) in limits {
+                let Some((cur, max)) = limit else {
+                    continue;
+                };
+                let limit = libc::rlimit {
+                    rlim_cur: cur as libc::rlim_t,
+                    rlim_max: max as libc::rlim_t,
+                };
+                if libc::setrlimit(resource, &limit) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            #[cfg(target_os = "linux")]
+            if no_new_privs && libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            #[cfg(not(target_os = "linux"))]
+            let _ = no_new_privs;
+            Ok(())
+        });
+    }
+
+    Ok(cgroup)
+}
+
+#[cfg(not(unix))]
+pub fn apply(
+    config: &SandboxConfig,
+    _cmd: &mut Command,
+    _server: &str,
+) -> io::Result<Option<Cgroup>> {
+    if *config != SandboxConfig::default() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "sandbox is only supported on unix",
+        ));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_rlimits() {
+        let config: SandboxConfig =
+            serde_json::from_str(r#"{ "openFiles": 64, "cpuTime": 10 }"#).unwrap();
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "ulimit -n; ulimit -t"]);
+        assert!(apply(&config, &mut cmd, "sh").unwrap().is_none());
+
+        let output = cmd.output().await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "64\n10\n");
+    }
+}
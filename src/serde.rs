@@ -1,32 +0,0 @@
-use crate::orphan::Local;
-use serde::__private::de::Content;
-use serde::de::Unexpected;
-pub use serde::*;
-
-impl<'de, 'a> From<&'a Content<'de>> for Local<Unexpected<'a>> {
-    fn from(value: &'a Content<'de>) -> Self {
-        Self(match *value {
-            Content::Bool(b) => Unexpected::Bool(b),
-            Content::U8(n) => Unexpected::Unsigned(n as u64),
-            Content::U16(n) => Unexpected::Unsigned(n as u64),
-            Content::U32(n) => Unexpected::Unsigned(n as u64),
-            Content::U64(n) => Unexpected::Unsigned(n),
-            Content::I8(n) => Unexpected::Signed(n as i64),
-            Content::I16(n) => Unexpected::Signed(n as i64),
-            Content::I32(n) => Unexpected::Signed(n as i64),
-            Content::I64(n) => Unexpected::Signed(n),
-            Content::F32(f) => Unexpected::Float(f as f64),
-            Content::F64(f) => Unexpected::Float(f),
-            Content::Char(c) => Unexpected::Char(c),
-            Content::String(ref s) => Unexpected::Str(s),
-            Content::Str(s) => Unexpected::Str(s),
-            Content::ByteBuf(ref b) => Unexpected::Bytes(b),
-            Content::Bytes(b) => Unexpected::Bytes(b),
-            Content::None | Content::Some(_) => Unexpected::Option,
-            Content::Unit => Unexpected::Unit,
-            Content::Newtype(_) => Unexpected::NewtypeStruct,
-            Content::Seq(_) => Unexpected::Seq,
-            Content::Map(_) => Unexpected::Map,
-        })
-    }
-}
@@ -0,0 +1,151 @@
+use std::{
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use rmcp::model::{ClientInfo, ClientRequest};
+use tokio::sync::Mutex;
+
+use crate::cache::Cache;
+use crate::config::McpServerConfig;
+use crate::error::Error;
+use crate::process::{StderrLog, StderrSink};
+use crate::upstream::{Upstream, UpstreamClient};
+
+/// How long a pooled client has to answer the ping checking it before it is handed out.
+const POOLED_PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// State shared by every session of one configured server.
+#[derive(Debug)]
+pub struct McpServer {
+    config: Arc<McpServerConfig>,
+    cache: Arc<Cache>,
+    stderr: Arc<StderrLog>,
+    shared: Mutex<Weak<UpstreamClient>>,
+    pool: Mutex<Vec<Arc<UpstreamClient>>>,
+    filling: AtomicBool,
+}
+
+impl McpServer {
+    pub fn new(name: Arc<str>, config: Arc<McpServerConfig>) -> Self {
+        let cache = Arc::new(Cache::new(config.options().cache().cloned()));
+        let stderr = Arc::new(StderrLog::new(name, config.stderr()));
+        Self {
+            config,
+            cache,
+            stderr,
+            shared: Default::default(),
+            pool: Default::default(),
+            filling: Default::default(),
+        }
+    }
+
+    pub fn config(&self) -> &McpServerConfig {
+        &self.config
+    }
+
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    pub fn stderr(&self) -> &StderrLog {
+        &self.stderr
+    }
+
+    /// Returns an upstream client for a session, reusing the shared one if configured.
+    ///
+    /// The shared client is only kept alive by the sessions using it, so it is torn down
+    /// once the last of them goes away or turns idle.
+    pub async fn connect(
+        self: &Arc<Self>,
+        client_info: Option<ClientInfo>,
+        session: u64,
+    ) -> Result<Arc<UpstreamClient>, Error> {
+        if !self.config.options().share_upstream() {
+            return self.take_or_create_client(client_info, Some(session)).await;
+        }
+
+        let mut shared = self.shared.lock().await;
+        if let Some(client) = shared.upgrade() {
+            return Ok(client);
+        }
+        let client = self.take_or_create_client(client_info, None).await?;
+        *shared = Arc::downgrade(&client);
+        Ok(client)
+    }
+
+    async fn take_or_create_client(
+        self: &Arc<Self>,
+        client_info: Option<ClientInfo>,
+        session: Option<u64>,
+    ) -> Result<Arc<UpstreamClient>, Error> {
+        let pooled = self.pool.lock().await.pop();
+        let Some(client) = pooled else {
+            return self.create_client(client_info, session).await;
+        };
+        self.refill();
+        if is_alive(&client).await {
+            return Ok(client);
+        }
+        tracing::warn!("prewarmed upstream is gone, starting a fresh one");
+        drop(client);
+        self.create_client(client_info, session).await
+    }
+
+    /// Tops the pool up to `prewarm` ready clients in the background.
+    ///
+    /// Pooled clients are started before any session exists, so they always present the
+    /// gateway identity upstream.
+    pub fn refill(self: &Arc<Self>) {
+        let prewarm = self.config.options().prewarm();
+        if prewarm == 0 || self.filling.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let server = Arc::downgrade(self);
+        tokio::spawn(async move {
+            while let Some(server) = server.upgrade() {
+                if server.pool.lock().await.len() >= prewarm {
+                    server.filling.store(false, Ordering::Release);
+                    break;
+                }
+                match server.create_client(None, None).await {
+                    Ok(client) => server.pool.lock().await.push(client),
+                    Err(e) => {
+                        tracing::error!(error = %e, "failed to prewarm upstream");
+                        server.filling.store(false, Ordering::Release);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    async fn create_client(
+        &self,
+        client_info: Option<ClientInfo>,
+        session: Option<u64>,
+    ) -> Result<Arc<UpstreamClient>, Error> {
+        let upstream = Upstream::new(self.config.client_info(client_info), self.cache.clone());
+        let stderr = StderrSink::new(self.stderr.clone(), session, upstream.exit().clone());
+        self.config.create_client(upstream, stderr).await
+    }
+}
+
+/// Whether a pooled client's process is still running and it still answers a ping.
+///
+/// Pooled clients may sit unused for long, during which the upstream can exit or drop
+/// the connection.
+async fn is_alive(client: &UpstreamClient) -> bool {
+    if client.service().exit().get().is_some() {
+        return false;
+    }
+    let ping = client.send_request(ClientRequest::PingRequest(Default::default()));
+    matches!(
+        tokio::time::timeout(POOLED_PING_TIMEOUT, ping).await,
+        Ok(Ok(_))
+    )
+}
@@ -0,0 +1,257 @@
+use std::{collections::HashMap, sync::Arc};
+
+use rmcp::{
+    Peer, RoleClient, RoleServer, ServiceError,
+    model::{ResourceUpdatedNotificationParam, SubscribeRequestParam, UnsubscribeRequestParam},
+};
+use tokio::sync::{Mutex, RwLock};
+
+/// Resource subscriptions of one upstream connection, shared by every session using it.
+///
+/// Each URI is subscribed upstream once, when the first session asks for it, and
+/// unsubscribed when the last interested session unsubscribes or goes away. The table is
+/// only locked to update who is subscribed; upstream calls are serialized per URI, so a
+/// slow upstream doesn't hold up the other sessions.
+#[derive(Debug)]
+pub struct Subscriptions<P = Peer<RoleServer>> {
+    uris: RwLock<HashMap<String, Subscription<P>>>,
+}
+
+#[derive(Debug)]
+struct Subscription<P> {
+    sessions: HashMap<u64, P>,
+    /// Whether the URI is subscribed upstream.
+    upstream: Arc<Mutex<bool>>,
+}
+
+impl<P> Default for Subscriptions<P> {
+    fn default() -> Self {
+        Self {
+            uris: Default::default(),
+        }
+    }
+}
+
+impl<P> Default for Subscription<P> {
+    fn default() -> Self {
+        Self {
+            sessions: Default::default(),
+            upstream: Default::default(),
+        }
+    }
+}
+
+impl Subscriptions {
+    pub async fn subscribe(
+        &self,
+        upstream: &Peer<RoleClient>,
+        session: u64,
+        peer: Peer<RoleServer>,
+        params: SubscribeRequestParam,
+    ) -> Result<(), ServiceError> {
+        let uri = params.uri.clone();
+        self.join(session, peer, uri, || upstream.subscribe(params))
+            .await
+    }
+
+    pub async fn unsubscribe(
+        &self,
+        upstream: &Peer<RoleClient>,
+        session: u64,
+        params: UnsubscribeRequestParam,
+    ) -> Result<(), ServiceError> {
+        let uri = params.uri.clone();
+        self.part(session, &uri, || upstream.unsubscribe(params))
+            .await
+    }
+
+    /// Drops every subscription held by a session that went away.
+    pub async fn leave(&self, upstream: &Peer<RoleClient>, session: u64) {
+        let uris = self
+            .uris
+            .read()
+            .await
+            .iter()
+            .filter(|(_, s)| s.sessions.contains_key(&session))
+            .map(|(uri, _)| uri.clone())
+            .collect::<Vec<_>>();
+        for uri in uris {
+            let unsubscribe = || upstream.unsubscribe(UnsubscribeRequestParam { uri: uri.clone() });
+            if let Err(e) = self.part(session, &uri, unsubscribe).await {
+                tracing::warn!("failed to unsubscribe: {e}");
+            }
+        }
+    }
+
+    /// Fans an upstream `resources/updated` out to every subscribed session.
+    pub async fn notify(&self, params: ResourceUpdatedNotificationParam) {
+        let peers = self
+            .uris
+            .read()
+            .await
+            .get(&params.uri)
+            .map(|s| s.sessions.values().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        for peer in peers {
+            if let Err(e) = peer.notify_resource_updated(params.clone()).await {
+                tracing::debug!("failed to forward resources/updated: {e}");
+            }
+        }
+    }
+}
+
+impl<P> Subscriptions<P> {
+    pub async fn contains_session(&self, session: u64) -> bool {
+        self.uris
+            .read()
+            .await
+            .values()
+            .any(|s| s.sessions.contains_key(&session))
+    }
+
+    /// Adds a session to the subscribers of `uri`, subscribing upstream if it is the first.
+    async fn join<T, E, F>(
+        &self,
+        session: u64,
+        peer: P,
+        uri: String,
+        subscribe: impl FnOnce() -> F,
+    ) -> Result<(), E>
+    where
+        F: Future<Output = Result<T, E>>,
+    {
+        let upstream = {
+            let mut uris = self.uris.write().await;
+            let subscription = uris.entry(uri.clone()).or_default();
+            subscription.sessions.insert(session, peer);
+            subscription.upstream.clone()
+        };
+
+        let mut subscribed = upstream.lock().await;
+        if *subscribed {
+            return Ok(());
+        }
+        match subscribe().await {
+            Ok(_) => {
+                *subscribed = true;
+                Ok(())
+            }
+            Err(e) => {
+                let mut uris = self.uris.write().await;
+                if let Some(subscription) = uris.get_mut(&uri)
+                    && Arc::ptr_eq(&subscription.upstream, &upstream)
+                {
+                    subscription.sessions.remove(&session);
+                    if subscription.sessions.is_empty() {
+                        uris.remove(&uri);
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Removes a session from the subscribers of `uri`, unsubscribing upstream if it was
+    /// the last one.
+    async fn part<T, E, F>(
+        &self,
+        session: u64,
+        uri: &str,
+        unsubscribe: impl FnOnce() -> F,
+    ) -> Result<(), E>
+    where
+        F: Future<Output = Result<T, E>>,
+    {
+        let upstream = {
+            let mut uris = self.uris.write().await;
+            let Some(subscription) = uris.get_mut(uri) else {
+                return Ok(());
+            };
+            if subscription.sessions.remove(&session).is_none() || !subscription.sessions.is_empty()
+            {
+                return Ok(());
+            }
+            subscription.upstream.clone()
+        };
+
+        let mut subscribed = upstream.lock().await;
+        // Another session may have subscribed while waiting for the lock.
+        let unused = self
+            .uris
+            .read()
+            .await
+            .get(uri)
+            .is_none_or(|s| s.sessions.is_empty());
+        let res = if *subscribed && unused {
+            *subscribed = false;
+            unsubscribe().await.map(|_| ())
+        } else {
+            Ok(())
+        };
+
+        let mut uris = self.uris.write().await;
+        if uris
+            .get(uri)
+            .is_some_and(|s| s.sessions.is_empty() && Arc::ptr_eq(&s.upstream, &upstream))
+        {
+            uris.remove(uri);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ref_counting() {
+        let subscriptions = Subscriptions::<()>::default();
+        let calls = std::sync::Mutex::new(Vec::new());
+        let call = |name: &'static str| {
+            calls.lock().unwrap().push(name);
+            async { Ok::<_, Infallible>(()) }
+        };
+
+        let subscribe = || call("subscribe");
+        subscriptions
+            .join(1, (), "a".into(), subscribe)
+            .await
+            .unwrap();
+        let subscribe = || call("subscribe");
+        subscriptions
+            .join(2, (), "a".into(), subscribe)
+            .await
+            .unwrap();
+        assert_eq!(*calls.lock().unwrap(), ["subscribe"]);
+
+        let unsubscribe = || call("unsubscribe");
+        subscriptions.part(1, "a", unsubscribe).await.unwrap();
+        assert_eq!(*calls.lock().unwrap(), ["subscribe"]);
+        assert!(subscriptions.contains_session(2).await);
+
+        let unsubscribe = || call("unsubscribe");
+        subscriptions.part(2, "a", unsubscribe).await.unwrap();
+        assert_eq!(*calls.lock().unwrap(), ["subscribe", "unsubscribe"]);
+        assert!(subscriptions.uris.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_subscribe() {
+        let subscriptions = Subscriptions::<()>::default();
+
+        let res = subscriptions
+            .join(1, (), "a".into(), || async { Err::<(), _>("unavailable") })
+            .await;
+        assert_eq!(res, Err("unavailable"));
+        assert!(!subscriptions.contains_session(1).await);
+
+        let res = subscriptions
+            .join(1, (), "a".into(), || async { Ok::<_, &str>(()) })
+            .await;
+        assert_eq!(res, Ok(()));
+        assert!(subscriptions.contains_session(1).await);
+    }
+}
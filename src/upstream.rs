@@ -0,0 +1,93 @@
+use std::sync::{Arc, OnceLock};
+
+use rmcp::Error as McpError;
+use rmcp::{
+    RoleClient, Service,
+    model::{
+        ClientInfo, ClientResult, ErrorCode, ListRootsResult, ServerNotification, ServerRequest,
+    },
+    service::{RequestContext, RunningService, ServiceRole},
+};
+
+use crate::cache::Cache;
+use crate::subscription::Subscriptions;
+
+pub type UpstreamClient = RunningService<RoleClient, Upstream>;
+
+/// Client side of the gateway, handling what the upstream server sends back to us.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    info: ClientInfo,
+    cache: Arc<Cache>,
+    subscriptions: Arc<Subscriptions>,
+    exit: Arc<OnceLock<String>>,
+}
+
+impl Upstream {
+    pub fn new(info: ClientInfo, cache: Arc<Cache>) -> Self {
+        Self {
+            info,
+            cache,
+            subscriptions: Default::default(),
+            exit: Default::default(),
+        }
+    }
+
+    /// Why the upstream process died on its own, once it has.
+    pub fn exit(&self) -> &Arc<OnceLock<String>> {
+        &self.exit
+    }
+
+    pub fn subscriptions(&self) -> &Subscriptions {
+        &self.subscriptions
+    }
+}
+
+impl Service<RoleClient> for Upstream {
+    async fn handle_request(
+        &self,
+        request: <RoleClient as ServiceRole>::PeerReq,
+        _ctx: RequestContext<RoleClient>,
+    ) -> Result<<RoleClient as ServiceRole>::Resp, McpError> {
+        match request {
+            ServerRequest::PingRequest(_) => Ok(ClientResult::empty(())),
+            ServerRequest::CreateMessageRequest(_) => Err(McpError::new(
+                ErrorCode::METHOD_NOT_FOUND,
+                "sampling/createMessage",
+                None,
+            )),
+            ServerRequest::ListRootsRequest(_) => {
+                Ok(ClientResult::ListRootsResult(ListRootsResult::default()))
+            }
+        }
+    }
+
+    async fn handle_notification(
+        &self,
+        notification: <RoleClient as ServiceRole>::PeerNot,
+    ) -> Result<(), McpError> {
+        match notification {
+            ServerNotification::ToolListChangedNotification(_) => {
+                self.cache.invalidate_tools().await
+            }
+            ServerNotification::PromptListChangedNotification(_) => {
+                self.cache.invalidate_prompts().await
+            }
+            ServerNotification::ResourceListChangedNotification(_) => {
+                self.cache.invalidate_resources().await
+            }
+            ServerNotification::ResourceUpdatedNotification(notification) => {
+                self.cache.invalidate_read(&notification.params.uri).await;
+                self.subscriptions.notify(notification.params).await;
+            }
+            ServerNotification::CancelledNotification(_)
+            | ServerNotification::ProgressNotification(_)
+            | ServerNotification::LoggingMessageNotification(_) => (),
+        }
+        Ok(())
+    }
+
+    fn get_info(&self) -> <RoleClient as ServiceRole>::Info {
+        self.info.clone()
+    }
+}
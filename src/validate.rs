@@ -0,0 +1,213 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value;
+
+use crate::config::{Config, McpServerConfig, WorkingDir};
+
+/// Server names that would shadow the gateway's own routes.
+const RESERVED_NAMES: &[&str] = &[crate::DISCOVERY];
+
+/// Problems found in a config, collected so they can be reported at once.
+#[derive(Debug, Default)]
+pub struct Problems {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl Problems {
+    /// Flags keys of a raw config file, at any depth, that no config type takes.
+    pub fn unknown_fields(&mut self, file: &Path, raw: &Value) {
+        let mut unknown = Vec::new();
+        // Invalid values are reported when the config is deserialized for real.
+        let _: Result<Config, _> = serde_ignored::deserialize(raw, |path| {
+            let path = path.to_string();
+            // `$schema` only points editors at the schema.
+            if path != "$schema" {
+                unknown.push(path);
+            }
+        });
+        for key in ["mcpServers", "servers"] {
+            let Some(Value::Object(servers)) = raw.get(key) else {
+                continue;
+            };
+            for (name, server) in servers {
+                let fields = McpServerConfig::unknown_fields(server);
+                unknown.extend(fields.iter().map(|field| format!("{key}.{name}.{field}")));
+            }
+        }
+
+        for path in unknown {
+            // `serde_ignored` marks the value of an `Option` with `?`.
+            let path = path.replace(".?", "");
+            self.warnings.push(match path.rsplit_once('.') {
+                Some((parent, field)) => {
+                    format!("{}: {parent}: unknown field `{field}`", file.display())
+                }
+                None => format!("{}: unknown field `{path}`", file.display()),
+            });
+        }
+    }
+
+    pub fn warn(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+
+    /// Checks server names, working directories and commands of a merged config.
+    pub fn check(&mut self, config: &Config) {
+        let mut names = config.servers.keys().collect::<Vec<_>>();
+        names.sort();
+
+        let mut lowercase = HashMap::new();
+        for name in names {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '~'))
+            {
+                self.errors.push(format!(
+                    "server `{name}`: name must only contain ASCII letters, digits, `-`, `_`, `.` and `~`"
+                ));
+            }
+            if matches!(&**name, "." | "..") {
+                self.errors.push(format!(
+                    "server `{name}`: name must not be a `.` or `..` path segment, which URLs drop"
+                ));
+            }
+            if RESERVED_NAMES.contains(&name.to_ascii_lowercase().as_str()) {
+                self.errors
+                    .push(format!("server `{name}`: name is reserved by the gateway"));
+            }
+            if let Some(other) = lowercase.insert(name.to_ascii_lowercase(), name) {
+                self.errors.push(format!(
+                    "server `{name}`: name differs from `{other}` only by case"
+                ));
+            }
+            // Reads and calls are keyed by client input, so without a ttl they'd grow forever.
+            if let Some(cache) = config.servers[name].options().cache()
+                && (cache.reads() || cache.idempotent_tools())
+                && cache.ttl().is_none()
+            {
+                self.errors.push(format!(
+                    "server `{name}`: cache.ttl is required with cache.reads or cache.idempotentTools"
+                ));
+            }
+
+            let McpServerConfig::Stdio(server) = &*config.servers[name] else {
+                continue;
+            };
+            let dirs = match server.cwd() {
+                Some(WorkingDir::Path(cwd)) => vec![("cwd", cwd.as_path())],
+                Some(WorkingDir::Session(session)) => session
+                    .template()
+                    .map(|t| ("cwd.template", t))
+                    .into_iter()
+                    .chain(session.root().map(|r| ("cwd.root", r)))
+                    .collect(),
+                None => vec![],
+            };
+            for (field, dir) in dirs {
+                if !dir.is_dir() {
+                    self.warnings.push(format!(
+                        "server `{name}`: {field} {} is not a directory",
+                        dir.display()
+                    ));
+                }
+            }
+            if resolve_command(server.command()).is_none() {
+                self.warnings.push(format!(
+                    "server `{name}`: command `{}` is not found on PATH",
+                    server.command()
+                ));
+            }
+        }
+    }
+
+    /// Fails with every error, and every warning too when `strict`; logs warnings otherwise.
+    pub fn finish(mut self, strict: bool) -> anyhow::Result<()> {
+        if strict {
+            self.errors.append(&mut self.warnings);
+        }
+        for warning in &self.warnings {
+            tracing::warn!("{warning}");
+        }
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        anyhow::bail!(
+            "invalid config:\n{}",
+            self.errors
+                .iter()
+                .map(|e| format!("  - {e}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    }
+}
+
+/// Finds the executable a command runs, looking it up on PATH unless it is a path.
+fn resolve_command(command: &str) -> Option<PathBuf> {
+    let path = Path::new(command);
+    if path.components().count() > 1 {
+        return path.is_file().then(|| path.to_path_buf());
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(command))
+        .find(|candidate| candidate.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_problems() {
+        let raw = serde_json::json!({
+            "mcpServers": {
+                "fetch": {
+                    "command": "sh",
+                    "args": [],
+                    "agrs": [],
+                    "sandbox": { "memry": 1024 }
+                },
+                "Fetch": {
+                    "type": "sse",
+                    "url": "http://localhost/sse",
+                    "command": "sh",
+                    "identity": { "mdoe": "static" },
+                    "cache": { "reads": true }
+                },
+                "a/b": { "command": "mcpgate-test-missing", "args": [] },
+                ".": { "command": "sh", "args": [] },
+                "..": { "command": "sh", "args": [] }
+            }
+        });
+        let mut problems = Problems::default();
+        problems.unknown_fields(Path::new("config.json"), &raw);
+        problems.check(&serde_json::from_value(raw).unwrap());
+
+        assert_eq!(
+            problems.warnings,
+            [
+                "config.json: mcpServers.Fetch: unknown field `command`",
+                "config.json: mcpServers.Fetch.identity: unknown field `mdoe`",
+                "config.json: mcpServers.fetch: unknown field `agrs`",
+                "config.json: mcpServers.fetch.sandbox: unknown field `memry`",
+                "server `a/b`: command `mcpgate-test-missing` is not found on PATH",
+            ]
+        );
+        assert_eq!(
+            problems.errors,
+            [
+                "server `.`: name must not be a `.` or `..` path segment, which URLs drop",
+                "server `..`: name must not be a `.` or `..` path segment, which URLs drop",
+                "server `Fetch`: cache.ttl is required with cache.reads or cache.idempotentTools",
+                "server `a/b`: name must only contain ASCII letters, digits, `-`, `_`, `.` and `~`",
+                "server `fetch`: name differs from `Fetch` only by case",
+            ]
+        );
+        assert!(problems.finish(false).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::config::{SandboxConfig, SessionDirConfig};
+
+/// Placeholder in args and env values replaced by the session directory.
+pub const SESSION_DIR: &str = "${SESSION_DIR}";
+
+/// Working directory created for one server process, removed once the process has
+/// exited unless configured to be kept.
+///
+/// The directory belongs to the process rather than the session, so with `shareUpstream`
+/// every session served by that process sees the same one.
+#[derive(Debug)]
+pub struct SessionDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl SessionDir {
+    /// Creates a directory with an unpredictable name, only accessible to its owner, which
+    /// is handed over to the sandbox user if one is configured.
+    pub fn create(config: &SessionDirConfig, sandbox: &SandboxConfig) -> io::Result<Self> {
+        let root = match config.root() {
+            Some(root) => {
+                std::fs::create_dir_all(root)?;
+                root.to_path_buf()
+            }
+            None => std::env::temp_dir(),
+        };
+        let mut builder = tempfile::Builder::new();
+        builder.prefix("mcpgate-");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            builder.permissions(std::fs::Permissions::from_mode(0o700));
+        }
+        let path = builder.tempdir_in(&root)?.keep();
+        let dir = Self {
+            path,
+            keep: config.keep(),
+        };
+
+        if let Some(template) = config.template() {
+            copy_dir(template, &dir.path).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("failed to copy template {}: {e}", template.display()),
+                )
+            })?;
+        }
+        #[cfg(unix)]
+        if sandbox.uid().is_some() || sandbox.gid().is_some() {
+            chown_all(&dir.path, sandbox.uid(), sandbox.gid())?;
+        }
+        #[cfg(not(unix))]
+        let _ = sandbox;
+        Ok(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replaces the placeholder in an arg or env value.
+    pub fn expand(&self, value: &str) -> String {
+        value.replace(SESSION_DIR, &self.path.to_string_lossy())
+    }
+}
+
+impl Drop for SessionDir {
+    fn drop(&mut self) {
+        if self.keep {
+            tracing::info!("keeping session directory {}", self.path.display());
+            return;
+        }
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            tracing::warn!(
+                "failed to remove session directory {}: {e}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            std::fs::create_dir(&target)?;
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn chown_all(path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    std::os::unix::fs::lchown(path, uid, gid)?;
+    if std::fs::symlink_metadata(path)?.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            chown_all(&entry?.path(), uid, gid)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_dir() {
+        let template =
+            std::env::temp_dir().join(format!("mcpgate-template-{}", std::process::id()));
+        std::fs::create_dir_all(template.join("nested")).unwrap();
+        std::fs::write(template.join("nested/seed.txt"), "seed").unwrap();
+
+        let config = serde_json::from_value::<SessionDirConfig>(serde_json::json!({
+            "mode": "session",
+            "template": template,
+        }))
+        .unwrap();
+        let dir = SessionDir::create(&config, &Default::default()).unwrap();
+        let path = dir.path().to_path_buf();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+        assert_eq!(
+            std::fs::read_to_string(path.join("nested/seed.txt")).unwrap(),
+            "seed"
+        );
+        assert_eq!(
+            dir.expand("--root=${SESSION_DIR}"),
+            format!("--root={}", path.display())
+        );
+
+        drop(dir);
+        assert!(!path.exists());
+        std::fs::remove_dir_all(template).unwrap();
+    }
+}